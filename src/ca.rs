@@ -1,3 +1,5 @@
+use std::path::Path;
+
 use eyre::Context;
 use rcgen::{
     BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
@@ -8,6 +10,8 @@ use rustls::sign::CertifiedKey;
 use rustls_pki_types::pem::PemObject;
 use rustls_pki_types::{CertificateDer, PrivateKeyDer};
 use time::{Duration, OffsetDateTime, Time};
+use tokio::fs;
+use tracing::info;
 use x509_parser::prelude::{FromDer, X509Certificate};
 
 /// Represents a CA capable of signing certificates
@@ -107,26 +111,11 @@ impl SigningCA {
 
     /// Create a temporary 30-day certificate for hostname
     pub fn create_cert_for_names(&self, names: Vec<SanType>) -> CertificateWithKey {
-        let mut params = CertificateParams::new(vec![]).unwrap();
-        let common_name: &str = match &names[0] {
-            SanType::Rfc822Name(str) | SanType::DnsName(str) | SanType::URI(str) => str.as_str(),
-            SanType::IpAddress(addr) => &addr.to_string(),
-            SanType::OtherName((_, rcgen::OtherNameValue::Utf8String(str))) => str,
-            _ => panic!("unknown or unsupported SAN type"),
-        };
-        params
-            .distinguished_name
-            .push(DnType::CommonName, common_name);
-        params.subject_alt_names = names;
-        params.is_ca = IsCa::ExplicitNoCa;
-        params.key_usages.push(KeyUsagePurpose::DigitalSignature);
-        params.extended_key_usages.extend_from_slice(&[
-            ExtendedKeyUsagePurpose::ServerAuth,
-            ExtendedKeyUsagePurpose::ClientAuth,
-        ]);
-        params.not_before = OffsetDateTime::now_utc().replace_time(Time::MIDNIGHT);
-        params.not_after = params.not_before + Duration::days(30);
+        self.sign_leaf(leaf_params(names))
+    }
 
+    /// Sign leaf certificate parameters with a freshly generated key
+    pub fn sign_leaf(&self, params: CertificateParams) -> CertificateWithKey {
         let keypair = KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256)
             .expect("failed to generate ECC P-256 keypair");
         self.sign_certificate(params, keypair)
@@ -134,7 +123,43 @@ impl SigningCA {
     }
 }
 
+/// Parameters for a 30-day server certificate for names, with the first name
+/// as the common name
+pub fn leaf_params(names: Vec<SanType>) -> CertificateParams {
+    let mut params = CertificateParams::new(vec![]).unwrap();
+    let common_name: &str = match &names[0] {
+        SanType::Rfc822Name(str) | SanType::DnsName(str) | SanType::URI(str) => str.as_str(),
+        SanType::IpAddress(addr) => &addr.to_string(),
+        SanType::OtherName((_, rcgen::OtherNameValue::Utf8String(str))) => str,
+        _ => panic!("unknown or unsupported SAN type"),
+    };
+    params
+        .distinguished_name
+        .push(DnType::CommonName, common_name);
+    params.subject_alt_names = names;
+    params.is_ca = IsCa::ExplicitNoCa;
+    params.key_usages.push(KeyUsagePurpose::DigitalSignature);
+    params.extended_key_usages.extend_from_slice(&[
+        ExtendedKeyUsagePurpose::ServerAuth,
+        ExtendedKeyUsagePurpose::ClientAuth,
+    ]);
+    params.not_before = OffsetDateTime::now_utc().replace_time(Time::MIDNIGHT);
+    params.not_after = params.not_before + Duration::days(30);
+    params
+}
+
 impl CertificateWithKey {
+    /// Expiry time of the end-entity certificate
+    pub fn not_after(&self) -> eyre::Result<OffsetDateTime> {
+        let leaf = self
+            .certificate_chain
+            .first()
+            .ok_or_else(|| eyre::eyre!("empty certificate chain"))?;
+        let (_, parsed) =
+            X509Certificate::from_der(leaf).wrap_err("failed to parse certificate")?;
+        Ok(parsed.validity().not_after.to_datetime())
+    }
+
     pub fn into_certified_key(self, crypto_provider: &CryptoProvider) -> CertifiedKey {
         CertifiedKey::new(
             self.certificate_chain,
@@ -145,3 +170,38 @@ impl CertificateWithKey {
         )
     }
 }
+
+pub fn pem_encode(tag: impl ToString, contents: Vec<u8>) -> String {
+    pem::encode(&pem::Pem::new(tag, contents))
+}
+
+/// Load the CA from `ca-cert.pem` and `ca-key.pem` in `data_dir`, creating it
+/// if it does not exist
+pub async fn load_or_create_ca(data_dir: &Path) -> eyre::Result<SigningCA> {
+    let cert_path = data_dir.join("ca-cert.pem");
+    let key_path = data_dir.join("ca-key.pem");
+    if let Ok((cert_pem, key_pem)) = tokio::try_join!(fs::read(&cert_path), fs::read(&key_path)) {
+        let out =
+            SigningCA::load_ca_pem(&cert_pem, &key_pem).wrap_err("parsing CA certificate/key")?;
+        info!("loaded CA certificate");
+        Ok(out)
+    } else {
+        let signing_ca = SigningCA::make_ca();
+        fs::create_dir_all(data_dir)
+            .await
+            .wrap_err("creating data directory")?;
+        tokio::try_join!(
+            fs::write(
+                &cert_path,
+                pem_encode("CERTIFICATE", signing_ca.cert.to_vec())
+            ),
+            fs::write(
+                &key_path,
+                pem_encode("PRIVATE KEY", signing_ca.key.secret_der().to_vec())
+            ),
+        )
+        .wrap_err("writing CA certificate")?;
+        info!("created CA certificate");
+        Ok(signing_ca)
+    }
+}
@@ -0,0 +1,215 @@
+//! Capturing HTTP bodies and raw streams while they are relayed
+
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use bytes::{Bytes, BytesMut};
+use hyper::HeaderMap;
+use hyper::body::{Body, Frame, SizeHint};
+use pin_project_lite::pin_project;
+use time::OffsetDateTime;
+use tokio::sync::oneshot;
+
+use crate::flow::{Direction, RawChunk};
+
+/// Body captured from a request or response
+#[derive(Debug, Clone, Default)]
+pub struct CapturedBody {
+    /// Up to the capture limit of body data
+    pub data: Bytes,
+    /// Total length of body data seen
+    pub length: u64,
+    pub trailers: Option<HeaderMap>,
+}
+
+impl CapturedBody {
+    /// Whether data past the capture limit was discarded
+    pub fn is_truncated(&self) -> bool {
+        self.length > self.data.len() as u64
+    }
+}
+
+/// Capture state, sent to the receiver when the body ends or is dropped
+struct Capture {
+    data: BytesMut,
+    length: u64,
+    trailers: Option<HeaderMap>,
+    limit: usize,
+    sender: Option<oneshot::Sender<CapturedBody>>,
+}
+
+impl Capture {
+    fn push(&mut self, data: &Bytes) {
+        self.length += data.len() as u64;
+        let remaining = self.limit.saturating_sub(self.data.len());
+        self.data
+            .extend_from_slice(&data[..remaining.min(data.len())]);
+    }
+
+    fn finish(&mut self) {
+        if let Some(sender) = self.sender.take() {
+            // the receiver may have lost interest
+            let _ = sender.send(CapturedBody {
+                data: std::mem::take(&mut self.data).freeze(),
+                length: self.length,
+                trailers: self.trailers.take(),
+            });
+        }
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+pin_project! {
+    /// Body which records frames passing through it
+    ///
+    /// The captured body is delivered once the inner body ends, or when this
+    /// body is dropped if it was not read to completion.
+    pub struct CaptureBody<B> {
+        #[pin]
+        inner: B,
+        capture: Capture,
+    }
+}
+
+impl<B> CaptureBody<B> {
+    /// Wrap `inner`, capturing at most `limit` bytes of data
+    pub fn new(inner: B, limit: usize) -> (Self, oneshot::Receiver<CapturedBody>) {
+        let (sender, receiver) = oneshot::channel();
+        let body = CaptureBody {
+            inner,
+            capture: Capture {
+                data: BytesMut::new(),
+                length: 0,
+                trailers: None,
+                limit,
+                sender: Some(sender),
+            },
+        };
+        (body, receiver)
+    }
+}
+
+impl<B: Body<Data = Bytes>> Body for CaptureBody<B> {
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+        let result = ready!(this.inner.poll_frame(cx));
+        match &result {
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    this.capture.push(data);
+                } else if let Some(trailers) = frame.trailers_ref() {
+                    this.capture.trailers = Some(trailers.clone());
+                }
+            }
+            Some(Err(_)) | None => this.capture.finish(),
+        }
+        Poll::Ready(result)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Timestamped chunks read from one direction of a raw stream
+pub struct StreamCapture {
+    direction: Direction,
+    chunks: Vec<RawChunk>,
+    captured: usize,
+    limit: usize,
+}
+
+impl StreamCapture {
+    /// Capture at most `limit` bytes of data
+    pub fn new(direction: Direction, limit: usize) -> Self {
+        StreamCapture {
+            direction,
+            chunks: Vec::new(),
+            captured: 0,
+            limit,
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        let take = self.limit.saturating_sub(self.captured).min(data.len());
+        if take == 0 {
+            return;
+        }
+        self.captured += take;
+        self.chunks.push(RawChunk {
+            direction: self.direction,
+            timestamp: OffsetDateTime::now_utc(),
+            data: Bytes::copy_from_slice(&data[..take]),
+        });
+    }
+
+    /// Interleave the chunks of both directions in the order they were read
+    pub fn merge(self, other: StreamCapture) -> Vec<RawChunk> {
+        let mut chunks = self.chunks;
+        chunks.extend(other.chunks);
+        // stable, so chunks with equal timestamps stay in order
+        chunks.sort_by_key(|chunk| chunk.timestamp);
+        chunks
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+    use http_body_util::{BodyExt, Full};
+
+    use super::{CaptureBody, StreamCapture};
+    use crate::flow::Direction;
+
+    #[tokio::test]
+    async fn capture_truncated() {
+        let (body, receiver) = CaptureBody::new(Full::new(Bytes::from_static(b"hello world")), 5);
+        let collected = body.collect().await.unwrap().to_bytes();
+        assert_eq!(&collected[..], b"hello world");
+
+        let captured = receiver.await.unwrap();
+        assert_eq!(&captured.data[..], b"hello");
+        assert_eq!(captured.length, 11);
+        assert!(captured.is_truncated());
+    }
+
+    #[tokio::test]
+    async fn capture_on_drop() {
+        let (body, receiver) = CaptureBody::new(Full::new(Bytes::from_static(b"unread")), 64);
+        drop(body);
+        let captured = receiver.await.unwrap();
+        assert_eq!(captured.length, 0);
+        assert!(!captured.is_truncated());
+    }
+
+    #[test]
+    fn stream_capture_limit() {
+        let mut sent = StreamCapture::new(Direction::ClientToUpstream, 8);
+        let mut received = StreamCapture::new(Direction::UpstreamToClient, 8);
+        sent.push(b"SSH-2.0-");
+        received.push(b"SSH-2.0-OpenSSH\r\n");
+        sent.push(b"client\r\n");
+
+        let chunks = sent.merge(received);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].direction, Direction::ClientToUpstream);
+        assert_eq!(&chunks[0].data[..], b"SSH-2.0-");
+        assert_eq!(chunks[1].direction, Direction::UpstreamToClient);
+        assert_eq!(&chunks[1].data[..], b"SSH-2.0-");
+    }
+}
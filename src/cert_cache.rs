@@ -0,0 +1,350 @@
+//! Cache for minted certificates
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use moka::Expiry;
+use moka::future::Cache;
+use rcgen::SanType;
+use rustls::crypto::CryptoProvider;
+use rustls::sign::CertifiedKey;
+use time::OffsetDateTime;
+use tracing::{debug, warn};
+
+use crate::ca::{CertificateWithKey, SigningCA};
+use crate::cert_store::CertificateStore;
+use crate::mimic::MimicTemplate;
+
+/// Minimum remaining validity of any certificate handed out
+///
+/// Minted certificates are valid for 30 days, so this also limits how long a
+/// single certificate is reused.
+pub const EXPIRY_MARGIN: Duration = Duration::from_secs(60 * 60 * 24 * 9);
+
+/// Minimum time a mimicked certificate is valid and reused for, even if
+/// upstream's certificate has already expired
+pub const MIN_MIMICKED_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Normalized set of subject alternative names
+///
+/// DNS names are lowercased with any trailing dot removed, and names are
+/// sorted and deduplicated so that equivalent sets compare equal.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SanSet(Vec<SanType>);
+
+impl SanSet {
+    pub fn new(names: Vec<SanType>) -> Self {
+        let mut names: Vec<SanType> = names
+            .into_iter()
+            .map(|name| match name {
+                SanType::DnsName(dns) => {
+                    let normalized = dns.as_str().trim_end_matches('.').to_ascii_lowercase();
+                    SanType::DnsName(normalized.try_into().unwrap_or(dns))
+                }
+                SanType::IpAddress(address) => SanType::IpAddress(address.to_canonical()),
+                other => other,
+            })
+            .collect();
+        names.sort_by_cached_key(sort_key);
+        names.dedup();
+        SanSet(names)
+    }
+
+    pub fn names(&self) -> &[SanType] {
+        &self.0
+    }
+}
+
+impl fmt::Display for SanSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, name) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            match name {
+                SanType::DnsName(dns) => write!(f, "dns:{}", dns.as_str())?,
+                SanType::IpAddress(address) => write!(f, "ip:{address}")?,
+                other => write!(f, "other:{other:?}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for SanSet {
+    type Err = eyre::Report;
+
+    /// Parse the format produced by `Display`, only DNS and IP names are
+    /// supported
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let names = s
+            .split(',')
+            .map(|name| match name.split_once(':') {
+                Some(("dns", dns)) => Ok(SanType::DnsName(dns.try_into()?)),
+                Some(("ip", address)) => Ok(SanType::IpAddress(address.parse::<IpAddr>()?)),
+                _ => Err(eyre::eyre!("unsupported name {name:?}")),
+            })
+            .collect::<eyre::Result<Vec<_>>>()?;
+        Ok(SanSet::new(names))
+    }
+}
+
+/// Key of a minted certificate
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CertificateKey {
+    pub names: SanSet,
+    /// Whether the certificate copies details from an upstream certificate
+    pub mimicked: bool,
+}
+
+impl CertificateKey {
+    /// Minimum remaining validity of the certificate when handed out
+    ///
+    /// Mimicked certificates expire along with upstream's, so they are used
+    /// until the end.
+    pub fn expiry_margin(&self) -> Duration {
+        if self.mimicked {
+            Duration::ZERO
+        } else {
+            EXPIRY_MARGIN
+        }
+    }
+}
+
+impl fmt::Display for CertificateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.mimicked {
+            f.write_str("mimic;")?;
+        }
+        self.names.fmt(f)
+    }
+}
+
+impl FromStr for CertificateKey {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (names, mimicked) = match s.strip_prefix("mimic;") {
+            Some(names) => (names, true),
+            None => (s, false),
+        };
+        Ok(CertificateKey {
+            names: names.parse()?,
+            mimicked,
+        })
+    }
+}
+
+fn sort_key(name: &SanType) -> (u8, String) {
+    match name {
+        SanType::DnsName(dns) => (0, dns.as_str().to_owned()),
+        SanType::IpAddress(address) => (1, address.to_string()),
+        SanType::Rfc822Name(email) => (2, email.as_str().to_owned()),
+        SanType::URI(uri) => (3, uri.as_str().to_owned()),
+        other => (4, format!("{other:?}")),
+    }
+}
+
+pub struct CertificateCacheConfig {
+    /// Maximum number of certificates to keep
+    pub max_capacity: u64,
+    /// How long a minted certificate may be reused for
+    pub time_to_live: Duration,
+}
+
+impl Default for CertificateCacheConfig {
+    fn default() -> Self {
+        CertificateCacheConfig {
+            max_capacity: 10_000,
+            time_to_live: Duration::from_secs(60 * 60 * 24 * 7),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct CacheEntry {
+    certified_key: Arc<CertifiedKey>,
+    expires_at: Instant,
+}
+
+/// Expire entries before their certificate gets close to expiry
+struct EntryExpiry;
+
+impl Expiry<CertificateKey, CacheEntry> for EntryExpiry {
+    fn expire_after_create(
+        &self,
+        _key: &CertificateKey,
+        value: &CacheEntry,
+        created_at: Instant,
+    ) -> Option<Duration> {
+        Some(value.expires_at.saturating_duration_since(created_at))
+    }
+}
+
+/// Cache of minted certificates keyed by SAN set and whether they were
+/// mimicked
+///
+/// If a store is provided, minted certificates are also persisted to it.
+pub struct CertificateCache {
+    cache: Cache<CertificateKey, CacheEntry>,
+    store: Option<CertificateStore>,
+    time_to_live: Duration,
+}
+
+impl CertificateCache {
+    pub fn new(config: CertificateCacheConfig, store: Option<CertificateStore>) -> Self {
+        let cache = Cache::builder()
+            .max_capacity(config.max_capacity)
+            .expire_after(EntryExpiry)
+            .build();
+        CertificateCache {
+            cache,
+            store,
+            time_to_live: config.time_to_live,
+        }
+    }
+
+    fn make_entry(
+        &self,
+        key: &CertificateKey,
+        certificate: CertificateWithKey,
+        not_after: OffsetDateTime,
+        crypto_provider: &CryptoProvider,
+    ) -> CacheEntry {
+        let remaining: Duration = (not_after - OffsetDateTime::now_utc() - key.expiry_margin())
+            .try_into()
+            .unwrap_or(Duration::ZERO);
+        let mut time_to_live = remaining.min(self.time_to_live);
+        if key.mimicked {
+            time_to_live = time_to_live.max(MIN_MIMICKED_TTL.min(self.time_to_live));
+        }
+        CacheEntry {
+            certified_key: Arc::new(certificate.into_certified_key(crypto_provider)),
+            expires_at: Instant::now() + time_to_live,
+        }
+    }
+
+    /// Populate the cache from the store
+    pub async fn load_from_store(&self, crypto_provider: &CryptoProvider) -> eyre::Result<()> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        for (key, stored) in store.load_all()? {
+            let entry =
+                self.make_entry(&key, stored.certificate, stored.not_after, crypto_provider);
+            self.cache.insert(key, entry).await;
+        }
+        Ok(())
+    }
+
+    /// Get a certificate for names from the cache or store, minting one if
+    /// necessary
+    ///
+    /// Concurrent calls for the same names will only mint one certificate. If
+    /// `template` is given, newly minted certificates copy its details, and
+    /// are cached separately from plain certificates for the same names.
+    pub async fn get_or_mint(
+        &self,
+        ca: &SigningCA,
+        crypto_provider: &CryptoProvider,
+        names: SanSet,
+        template: Option<&MimicTemplate>,
+    ) -> Arc<CertifiedKey> {
+        let key = CertificateKey {
+            names,
+            mimicked: template.is_some(),
+        };
+        let names = &key.names;
+        let entry = self
+            .cache
+            .get_with_by_ref(&key, async {
+                if let Some(store) = &self.store {
+                    match store.get(&key) {
+                        Ok(Some(stored)) => {
+                            debug!(%key, "loaded certificate from store");
+                            return self.make_entry(
+                                &key,
+                                stored.certificate,
+                                stored.not_after,
+                                crypto_provider,
+                            );
+                        }
+                        Ok(None) => {}
+                        Err(err) => warn!(?err, %key, "failed to read certificate store"),
+                    }
+                }
+
+                debug!(%key, "minting certificate");
+                let certificate = match template {
+                    Some(template) => ca.create_cert_mimicking(names.names().to_vec(), template),
+                    None => ca.create_cert_for_names(names.names().to_vec()),
+                };
+                let not_after = certificate
+                    .not_after()
+                    .expect("failed to parse minted certificate");
+                if let Some(store) = &self.store
+                    && let Err(err) = store.insert(&key, &certificate)
+                {
+                    warn!(?err, %key, "failed to persist certificate");
+                }
+                self.make_entry(&key, certificate, not_after, crypto_provider)
+            })
+            .await;
+        entry.certified_key
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use rcgen::SanType;
+
+    use super::{CertificateKey, SanSet};
+
+    fn dns(name: &str) -> SanType {
+        SanType::DnsName(name.try_into().unwrap())
+    }
+
+    #[test]
+    fn normalize_san_set() {
+        let a = SanSet::new(vec![
+            dns("Example.COM."),
+            SanType::IpAddress(IpAddr::V6(Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped())),
+            dns("example.com"),
+        ]);
+        let b = SanSet::new(vec![
+            SanType::IpAddress(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))),
+            dns("example.com"),
+        ]);
+        assert_eq!(a, b);
+        assert_eq!(a.names().len(), 2);
+        assert_ne!(
+            a,
+            SanSet::new(vec![SanType::IpAddress(Ipv6Addr::LOCALHOST.into())])
+        );
+        assert_eq!(a.to_string(), "dns:example.com,ip:10.0.0.1");
+        assert_eq!(a.to_string().parse::<SanSet>().unwrap(), a);
+    }
+
+    #[test]
+    fn certificate_key_format() {
+        let names = SanSet::new(vec![dns("example.com")]);
+        for mimicked in [false, true] {
+            let key = CertificateKey {
+                names: names.clone(),
+                mimicked,
+            };
+            assert_eq!(key.to_string().parse::<CertificateKey>().unwrap(), key);
+        }
+        // plain certificates keep the key format used before mimicking
+        let plain = CertificateKey {
+            names: names.clone(),
+            mimicked: false,
+        };
+        assert_eq!(plain.to_string(), names.to_string());
+    }
+}
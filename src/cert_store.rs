@@ -0,0 +1,224 @@
+//! Persistent storage for minted certificates
+
+use std::path::Path;
+
+use eyre::{Context, OptionExt, bail};
+use fjall::{Config, Keyspace, PartitionCreateOptions, PartitionHandle, PersistMode};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+use tracing::{debug, info, warn};
+
+use crate::ca::{CertificateWithKey, SigningCA};
+use crate::cert_cache::CertificateKey;
+
+const PARTITION_NAME: &str = "certificates";
+const FORMAT_VERSION: u8 = 1;
+
+/// Certificate loaded from the store
+pub struct StoredCertificate {
+    pub certificate: CertificateWithKey,
+    pub not_after: OffsetDateTime,
+}
+
+/// fjall-backed store of minted certificates
+///
+/// Entries are keyed by the SHA-256 fingerprint of the signing CA followed by
+/// the certificate key, so certificates signed by a previous CA are never
+/// returned.
+pub struct CertificateStore {
+    keyspace: Keyspace,
+    partition: PartitionHandle,
+    ca_cert: CertificateDer<'static>,
+    ca_fingerprint: [u8; 32],
+}
+
+impl CertificateStore {
+    pub fn open(path: &Path, ca: &SigningCA) -> eyre::Result<Self> {
+        let keyspace = Config::new(path)
+            .open()
+            .wrap_err("failed to open certificate store")?;
+        let partition = keyspace
+            .open_partition(PARTITION_NAME, PartitionCreateOptions::default())
+            .wrap_err("failed to open certificate store partition")?;
+
+        Ok(CertificateStore {
+            keyspace,
+            partition,
+            ca_cert: ca.cert.clone(),
+            ca_fingerprint: Sha256::digest(&ca.cert).into(),
+        })
+    }
+
+    fn key_for(&self, cert_key: &CertificateKey) -> Vec<u8> {
+        let mut out = self.ca_fingerprint.to_vec();
+        out.extend_from_slice(cert_key.to_string().as_bytes());
+        out
+    }
+
+    /// Whether a stored certificate is still worth using
+    fn is_usable(&self, cert_key: &CertificateKey, stored: &StoredCertificate) -> bool {
+        stored.certificate.certificate_chain.get(1) == Some(&self.ca_cert)
+            && stored.not_after - cert_key.expiry_margin() > OffsetDateTime::now_utc()
+    }
+
+    pub fn get(&self, cert_key: &CertificateKey) -> eyre::Result<Option<StoredCertificate>> {
+        let key = self.key_for(cert_key);
+        let Some(value) = self
+            .partition
+            .get(&key)
+            .wrap_err("failed to read certificate store")?
+        else {
+            return Ok(None);
+        };
+
+        match decode(&value) {
+            Ok(stored) if self.is_usable(cert_key, &stored) => Ok(Some(stored)),
+            result => {
+                if let Err(err) = result {
+                    warn!(?err, %cert_key, "discarding invalid stored certificate");
+                }
+                self.partition
+                    .remove(key)
+                    .wrap_err("failed to remove stored certificate")?;
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn insert(
+        &self,
+        cert_key: &CertificateKey,
+        certificate: &CertificateWithKey,
+    ) -> eyre::Result<()> {
+        self.partition
+            .insert(self.key_for(cert_key), encode(certificate))
+            .wrap_err("failed to write certificate store")
+    }
+
+    /// Load all usable certificates, removing entries which were signed by a
+    /// different CA or are close to expiry
+    pub fn load_all(&self) -> eyre::Result<Vec<(CertificateKey, StoredCertificate)>> {
+        let mut loaded = Vec::new();
+        let mut discarded = 0usize;
+        for entry in self.partition.iter() {
+            let (key, value) = entry.wrap_err("failed to read certificate store")?;
+            let cert_key = key
+                .strip_prefix(&self.ca_fingerprint[..])
+                .and_then(|cert_key| std::str::from_utf8(cert_key).ok())
+                .and_then(|cert_key| cert_key.parse::<CertificateKey>().ok());
+            let stored = cert_key.as_ref().and_then(|cert_key| {
+                decode(&value)
+                    .ok()
+                    .filter(|stored| self.is_usable(cert_key, stored))
+            });
+
+            match (cert_key, stored) {
+                (Some(cert_key), Some(stored)) => loaded.push((cert_key, stored)),
+                _ => {
+                    self.partition
+                        .remove(key)
+                        .wrap_err("failed to remove stored certificate")?;
+                    discarded += 1;
+                }
+            }
+        }
+
+        if discarded > 0 {
+            self.keyspace
+                .persist(PersistMode::SyncAll)
+                .wrap_err("failed to persist certificate store")?;
+        }
+        info!(
+            loaded = loaded.len(),
+            discarded, "loaded certificates from store"
+        );
+        Ok(loaded)
+    }
+}
+
+// format: version (u8), certificate count (u8), then each certificate and
+// finally the private key as u32 length-prefixed DER
+fn encode(certificate: &CertificateWithKey) -> Vec<u8> {
+    fn push_item(out: &mut Vec<u8>, item: &[u8]) {
+        out.extend_from_slice(&(item.len() as u32).to_be_bytes());
+        out.extend_from_slice(item);
+    }
+
+    let mut out = vec![FORMAT_VERSION, certificate.certificate_chain.len() as u8];
+    for cert in &certificate.certificate_chain {
+        push_item(&mut out, cert);
+    }
+    push_item(&mut out, certificate.key.secret_der());
+    out
+}
+
+fn decode(mut value: &[u8]) -> eyre::Result<StoredCertificate> {
+    fn take<'a>(value: &mut &'a [u8], count: usize) -> eyre::Result<&'a [u8]> {
+        let (item, rest) = value
+            .split_at_checked(count)
+            .ok_or_eyre("truncated certificate entry")?;
+        *value = rest;
+        Ok(item)
+    }
+    fn take_item<'a>(value: &mut &'a [u8]) -> eyre::Result<&'a [u8]> {
+        let length = u32::from_be_bytes(take(value, 4)?.try_into().unwrap());
+        take(value, length as usize)
+    }
+
+    let [version, count] = take(&mut value, 2)? else {
+        bail!("truncated certificate entry");
+    };
+    if *version != FORMAT_VERSION {
+        bail!("unknown certificate entry version {version}");
+    }
+    let mut certificate_chain = Vec::with_capacity(*count as usize);
+    for _ in 0..*count {
+        certificate_chain.push(CertificateDer::from(take_item(&mut value)?.to_vec()));
+    }
+    let key = PrivateKeyDer::try_from(take_item(&mut value)?.to_vec())
+        .map_err(|err| eyre::eyre!("invalid private key: {err}"))?;
+
+    let certificate = CertificateWithKey {
+        certificate_chain,
+        key,
+    };
+    let not_after = certificate.not_after()?;
+    debug!(%not_after, "decoded stored certificate");
+    Ok(StoredCertificate {
+        certificate,
+        not_after,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use rcgen::SanType;
+
+    use super::{decode, encode};
+    use crate::ca::SigningCA;
+
+    #[test]
+    fn round_trip() {
+        let ca = SigningCA::make_ca();
+        let certificate =
+            ca.create_cert_for_names(vec![SanType::DnsName("example.com".try_into().unwrap())]);
+        let encoded = encode(&certificate);
+
+        let decoded = decode(&encoded).unwrap();
+        assert_eq!(
+            decoded.certificate.certificate_chain,
+            certificate.certificate_chain
+        );
+        assert_eq!(
+            decoded.certificate.key.secret_der(),
+            certificate.key.secret_der()
+        );
+        assert_eq!(decoded.not_after, certificate.not_after().unwrap());
+
+        // corrupt entries are errors, whatever their length
+        for length in 0..encoded.len() {
+            assert!(decode(&encoded[..length]).is_err());
+        }
+    }
+}
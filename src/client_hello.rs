@@ -0,0 +1,361 @@
+//! TLS ClientHello parser
+
+use eyre::{OptionExt, bail};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::replay_buffer::ReplayBuffer;
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 0x01;
+/// Handshake messages are limited to 2^24 bytes, but nobody sends a
+/// ClientHello anywhere near that large
+const MAX_HANDSHAKE_LENGTH: usize = 1 << 16;
+/// Most bytes recorded while reading a ClientHello, leaving room for the
+/// headers of records it is fragmented into
+const MAX_RECORDED_LENGTH: usize = 2 * MAX_HANDSHAKE_LENGTH;
+
+pub const EXTENSION_SERVER_NAME: u16 = 0;
+pub const EXTENSION_ALPN: u16 = 16;
+pub const EXTENSION_SUPPORTED_VERSIONS: u16 = 43;
+
+/// Parsed TLS ClientHello
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientHello {
+    /// Record layer version of the first record
+    pub record_version: u16,
+    /// `legacy_version` field of the ClientHello
+    pub legacy_version: u16,
+    /// SNI hostname
+    pub server_name: Option<String>,
+    /// Offered ALPN protocols, in order of preference
+    pub alpn: Vec<Vec<u8>>,
+    /// Versions from the supported_versions extension
+    pub supported_versions: Vec<u16>,
+    pub cipher_suites: Vec<u16>,
+    /// All extensions as (type, data), in the order they were sent
+    pub extensions: Vec<(u16, Vec<u8>)>,
+}
+
+impl ClientHello {
+    pub fn extension(&self, extension_type: u16) -> Option<&[u8]> {
+        self.extensions
+            .iter()
+            .find(|(t, _)| *t == extension_type)
+            .map(|(_, data)| data.as_slice())
+    }
+
+    /// Highest TLS version offered by the client
+    pub fn max_version(&self) -> u16 {
+        self.supported_versions
+            .iter()
+            .copied()
+            // ignore GREASE values
+            .filter(|version| version & 0x0f0f != 0x0a0a)
+            .max()
+            .unwrap_or(self.legacy_version)
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn bytes(&mut self, count: usize) -> eyre::Result<&'a [u8]> {
+        let (out, rest) = self
+            .data
+            .split_at_checked(count)
+            .ok_or_eyre("truncated ClientHello")?;
+        self.data = rest;
+        Ok(out)
+    }
+
+    fn u8(&mut self) -> eyre::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> eyre::Result<u16> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> eyre::Result<usize> {
+        let bytes = self.bytes(3)?;
+        Ok(u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]) as usize)
+    }
+
+    /// Read a vector with a u8 length prefix
+    fn vec8(&mut self) -> eyre::Result<Reader<'a>> {
+        let length = self.u8()? as usize;
+        Ok(Reader::new(self.bytes(length)?))
+    }
+
+    /// Read a vector with a u16 length prefix
+    fn vec16(&mut self) -> eyre::Result<Reader<'a>> {
+        let length = self.u16()? as usize;
+        Ok(Reader::new(self.bytes(length)?))
+    }
+}
+
+/// Reassemble the first handshake message from TLS records
+///
+/// Returns `None` if more data is needed.
+fn reassemble(mut data: &[u8]) -> eyre::Result<Option<(u16, Vec<u8>)>> {
+    let mut record_version = None;
+    let mut handshake = Vec::new();
+    loop {
+        if handshake.len() >= 4 {
+            let length = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+            if length > MAX_HANDSHAKE_LENGTH {
+                bail!("ClientHello too large");
+            }
+            if handshake.len() >= 4 + length {
+                handshake.truncate(4 + length);
+                return Ok(Some((record_version.unwrap(), handshake)));
+            }
+        }
+
+        let Some(header) = data.get(..5) else {
+            return Ok(None);
+        };
+        if header[0] != CONTENT_TYPE_HANDSHAKE {
+            bail!("unexpected record type {:#04x}", header[0]);
+        }
+        let version = u16::from_be_bytes([header[1], header[2]]);
+        record_version.get_or_insert(version);
+        let length = u16::from_be_bytes([header[3], header[4]]) as usize;
+        if length == 0 {
+            bail!("empty handshake record");
+        }
+        let Some(fragment) = data.get(5..5 + length) else {
+            return Ok(None);
+        };
+        handshake.extend_from_slice(fragment);
+        data = &data[5 + length..];
+    }
+}
+
+/// Parse a ClientHello from raw TLS records, which may be fragmented
+///
+/// Returns `None` if more data is needed.
+pub fn parse(data: &[u8]) -> eyre::Result<Option<ClientHello>> {
+    let Some((record_version, handshake)) = reassemble(data)? else {
+        return Ok(None);
+    };
+
+    let mut reader = Reader::new(&handshake);
+    if reader.u8()? != HANDSHAKE_TYPE_CLIENT_HELLO {
+        bail!("first handshake message is not ClientHello");
+    }
+    let length = reader.u24()?;
+    let mut reader = Reader::new(reader.bytes(length)?);
+
+    let legacy_version = reader.u16()?;
+    let _random = reader.bytes(32)?;
+    let _session_id = reader.vec8()?;
+    let mut suites = reader.vec16()?;
+    let mut cipher_suites = Vec::new();
+    while !suites.is_empty() {
+        cipher_suites.push(suites.u16()?);
+    }
+    let _compression_methods = reader.vec8()?;
+
+    let mut hello = ClientHello {
+        record_version,
+        legacy_version,
+        server_name: None,
+        alpn: Vec::new(),
+        supported_versions: Vec::new(),
+        cipher_suites,
+        extensions: Vec::new(),
+    };
+
+    // extensions may be omitted entirely by very old clients
+    if reader.is_empty() {
+        return Ok(Some(hello));
+    }
+    let mut extensions = reader.vec16()?;
+    while !extensions.is_empty() {
+        let extension_type = extensions.u16()?;
+        let data = extensions.vec16()?.data;
+        match extension_type {
+            EXTENSION_SERVER_NAME => hello.server_name = parse_server_name(data)?,
+            EXTENSION_ALPN => {
+                let mut list = Reader::new(data).vec16()?;
+                while !list.is_empty() {
+                    hello.alpn.push(list.vec8()?.data.to_vec());
+                }
+            }
+            EXTENSION_SUPPORTED_VERSIONS => {
+                let mut list = Reader::new(data).vec8()?;
+                while !list.is_empty() {
+                    hello.supported_versions.push(list.u16()?);
+                }
+            }
+            _ => {}
+        }
+        hello.extensions.push((extension_type, data.to_vec()));
+    }
+
+    Ok(Some(hello))
+}
+
+fn parse_server_name(data: &[u8]) -> eyre::Result<Option<String>> {
+    let mut list = Reader::new(data).vec16()?;
+    while !list.is_empty() {
+        let name_type = list.u8()?;
+        let name = list.vec16()?.data;
+        // host_name is the only type ever defined. names which are not
+        // ASCII are ignored rather than failing the parse, so the connection
+        // can still be handled by its destination
+        if name_type == 0 {
+            let name = std::str::from_utf8(name)
+                .ok()
+                .filter(|name| name.is_ascii())
+                .map(str::to_ascii_lowercase);
+            return Ok(name);
+        }
+    }
+    Ok(None)
+}
+
+/// Read from stream until a complete ClientHello has been recorded
+///
+/// The replay buffer's limit is raised to fit any ClientHello the parser
+/// accepts. The replay buffer is not rewound.
+pub async fn read_client_hello<T: AsyncRead + Unpin>(
+    stream: &mut ReplayBuffer<T>,
+) -> eyre::Result<ClientHello> {
+    stream.raise_max_length(MAX_RECORDED_LENGTH);
+    let mut chunk = [0u8; 2048];
+    loop {
+        if let Some(hello) = parse(stream.recorded())? {
+            return Ok(hello);
+        }
+        if stream.read(&mut chunk).await? == 0 {
+            bail!("connection closed while reading ClientHello");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use rustls::{ClientConfig, ClientConnection, RootCertStore};
+
+    use super::{parse, read_client_hello};
+    use crate::replay_buffer::ReplayBuffer;
+
+    fn client_hello_bytes(server_name: &str, alpn: &[&[u8]]) -> Vec<u8> {
+        let mut config = ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::aws_lc_rs::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(RootCertStore::empty())
+        .with_no_client_auth();
+        config.alpn_protocols = alpn.iter().map(|proto| proto.to_vec()).collect();
+        let mut connection =
+            ClientConnection::new(Arc::new(config), server_name.to_owned().try_into().unwrap())
+                .unwrap();
+        let mut out = Vec::new();
+        connection.write_tls(&mut out).unwrap();
+        out
+    }
+
+    fn push_extension(out: &mut Vec<u8>, extension_type: u16, data: &[u8]) {
+        out.extend_from_slice(&extension_type.to_be_bytes());
+        out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        out.extend_from_slice(data);
+    }
+
+    /// TLS 1.2 ClientHello with SNI and `padding` bytes of padding extension,
+    /// in records of at most 16 KiB
+    fn padded_hello_bytes(server_name: &[u8], padding: usize) -> Vec<u8> {
+        let mut name = vec![0];
+        name.extend_from_slice(&(server_name.len() as u16).to_be_bytes());
+        name.extend_from_slice(server_name);
+        let mut list = (name.len() as u16).to_be_bytes().to_vec();
+        list.extend_from_slice(&name);
+        let mut extensions = Vec::new();
+        push_extension(&mut extensions, 0, &list);
+        push_extension(&mut extensions, 21, &vec![0; padding]);
+
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0; 32]);
+        // empty session ID, one cipher suite, null compression
+        body.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+        let mut handshake = vec![1];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&body);
+
+        let mut out = Vec::new();
+        for fragment in handshake.chunks(16384) {
+            out.extend_from_slice(&[0x16, 0x03, 0x01]);
+            out.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+            out.extend_from_slice(fragment);
+        }
+        out
+    }
+
+    #[test]
+    fn parse_rustls_hello() {
+        let bytes = client_hello_bytes("example.com", &[b"h2", b"http/1.1"]);
+        let hello = parse(&bytes).unwrap().unwrap();
+        assert_eq!(hello.server_name.as_deref(), Some("example.com"));
+        assert_eq!(hello.alpn, vec![b"h2".to_vec(), b"http/1.1".to_vec()]);
+        assert!(hello.supported_versions.contains(&0x0304));
+        assert_eq!(hello.max_version(), 0x0304);
+        assert!(!hello.cipher_suites.is_empty());
+
+        // incomplete input needs more data
+        assert_eq!(parse(&bytes[..bytes.len() - 1]).unwrap(), None);
+    }
+
+    #[test]
+    fn parse_fragmented_hello() {
+        let bytes = client_hello_bytes("fragmented.example", &[]);
+        let handshake = &bytes[5..];
+        let (first, second) = handshake.split_at(40);
+
+        let mut fragmented = Vec::new();
+        for fragment in [first, second] {
+            fragmented.extend_from_slice(&[0x16, 0x03, 0x01]);
+            fragmented.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+            fragmented.extend_from_slice(fragment);
+        }
+
+        let hello = parse(&fragmented).unwrap().unwrap();
+        assert_eq!(hello.server_name.as_deref(), Some("fragmented.example"));
+        assert!(hello.alpn.is_empty());
+        assert_eq!(parse(&fragmented[..50]).unwrap(), None);
+    }
+
+    #[test]
+    fn ignore_invalid_server_name() {
+        let bytes = padded_hello_bytes(b"exam\xffple.com", 0);
+        let hello = parse(&bytes).unwrap().unwrap();
+        assert_eq!(hello.server_name, None);
+    }
+
+    #[tokio::test]
+    async fn read_large_hello() {
+        let bytes = padded_hello_bytes(b"example.com", 30000);
+        // larger than the replay limit used for sniffing
+        let mut stream = ReplayBuffer::new(&bytes[..], 16384);
+        let hello = read_client_hello(&mut stream).await.unwrap();
+        assert_eq!(hello.server_name.as_deref(), Some("example.com"));
+        assert_eq!(stream.recorded(), bytes);
+    }
+}
@@ -1,3 +1,5 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+
 pub fn setup_log_handlers() {
     use tracing_error::ErrorLayer;
     use tracing_subscriber::prelude::*;
@@ -23,3 +25,8 @@ pub fn initialize_logging() {
     static INITIALIZE: Once = Once::new();
     INITIALIZE.call_once(setup_log_handlers);
 }
+
+/// Bidirectional stream which can be moved between tasks
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> AsyncStream for T {}
@@ -0,0 +1,235 @@
+//! Local answers to TRACE and to requests for the diagnostic host
+//!
+//! The response describes how the connection reached the proxy, to help debug
+//! client configuration, and echoes the request as it was received with
+//! credentials redacted.
+
+use std::fmt::Write;
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Limited};
+use hyper::body::Body;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::http::request::Parts;
+use hyper::http::uri::Authority;
+use hyper::{Method, Request, Response, StatusCode, header};
+use sha2::{Digest, Sha256};
+use tracing::debug;
+
+use crate::http1::{ProxyBody, text_response};
+use crate::server::ConnectionHandler;
+use crate::target::Host;
+use crate::upstream::UpstreamRoute;
+
+/// Reserved hostname which is always answered by the proxy
+pub const DIAGNOSTIC_HOST: &str = "rs-mitm.local";
+
+pub fn is_diagnostic_host(host: &Host) -> bool {
+    host.name() == Some(DIAGNOSTIC_HOST)
+}
+
+/// Host the request is addressed to, from the URI or the `Host` header
+fn request_host<B>(request: &Request<B>) -> Option<Host> {
+    if let Some(host) = request.uri().host() {
+        return Some(Host::parse(host));
+    }
+    let authority: Authority = request
+        .headers()
+        .get(header::HOST)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()?;
+    Some(Host::parse(authority.host()))
+}
+
+/// Whether a request should be answered locally
+///
+/// `route` is the upstream of the connection the request arrived on.
+pub fn is_diagnostic_request<B>(request: &Request<B>, route: Option<&UpstreamRoute>) -> bool {
+    request.method() == Method::TRACE
+        || route.is_some_and(|route| is_diagnostic_host(&route.target.host))
+        || request_host(request).is_some_and(|host| is_diagnostic_host(&host))
+}
+
+/// Request headers which are not echoed (RFC 9110 section 9.3.8)
+const REDACTED_HEADERS: &[HeaderName] = &[
+    header::AUTHORIZATION,
+    header::COOKIE,
+    header::PROXY_AUTHORIZATION,
+];
+
+/// Serialize a request head and body as a `message/http` document
+fn echo_request(parts: &Parts, body: &[u8]) -> Vec<u8> {
+    let mut out = format!("{} {} {:?}\r\n", parts.method, parts.uri, parts.version).into_bytes();
+    for (name, value) in &parts.headers {
+        out.extend_from_slice(name.as_str().as_bytes());
+        out.extend_from_slice(b": ");
+        if REDACTED_HEADERS.contains(name) {
+            out.extend_from_slice(b"<redacted>");
+        } else {
+            out.extend_from_slice(value.as_bytes());
+        }
+        out.extend_from_slice(b"\r\n");
+    }
+    out.extend_from_slice(b"\r\n");
+    out.extend_from_slice(body);
+    out
+}
+
+/// Format a fingerprint as colon separated hex, as `openssl x509` does
+fn fingerprint(der: &[u8]) -> String {
+    let digest = Sha256::digest(der);
+    let hex: Vec<_> = digest.iter().map(|byte| format!("{byte:02X}")).collect();
+    hex.join(":")
+}
+
+impl ConnectionHandler {
+    /// Answer a request with diagnostics instead of forwarding it
+    ///
+    /// `route` is the upstream of the connection the request arrived on.
+    pub(crate) async fn diagnostic_response<B>(
+        &self,
+        request: Request<B>,
+        route: Option<&UpstreamRoute>,
+    ) -> Response<ProxyBody>
+    where
+        B: Body,
+        B::Error: std::error::Error + Send + Sync + 'static,
+    {
+        debug!(peer_addr = %self.peer_addr, method = %request.method(), uri = %request.uri(), "answering diagnostic request");
+        let (parts, body) = request.into_parts();
+        let limit = self.config.max_body_capture;
+        let body = Limited::new(body, limit).collect().await;
+
+        // writing to a String can't fail
+        let mut out = String::new();
+        let _ = writeln!(out, "rs-mitm diagnostics");
+        let _ = writeln!(
+            out,
+            "listener: {} ({:?})",
+            self.config.bind_address, self.config.mode
+        );
+        let _ = writeln!(out, "client: {}", self.peer_addr);
+        if let Some(proxied) = &self.proxied {
+            let _ = writeln!(
+                out,
+                "proxied by: {} (destination {})",
+                proxied.proxy_addr, proxied.destination
+            );
+        }
+        if let Some(destination) = self.original_destination {
+            let _ = writeln!(out, "original destination: {destination}");
+        }
+        let _ = writeln!(out, "protocol: {:?}", parts.version);
+        match route {
+            Some(route) if route.tls => {
+                let _ = writeln!(out, "intercepted: yes, TLS to {}", route.target);
+            }
+            Some(route) => {
+                let _ = writeln!(out, "intercepted: no TLS, tunneled to {}", route.target);
+            }
+            None => {
+                let _ = writeln!(out, "intercepted: no TLS, sent directly to the proxy");
+            }
+        }
+        let _ = writeln!(
+            out,
+            "CA fingerprint (SHA-256): {}",
+            fingerprint(&self.shared.ca.cert)
+        );
+
+        let echo = match body {
+            Ok(body) => echo_request(&parts, &body.to_bytes()),
+            Err(err) => {
+                let _ = writeln!(out, "request body not echoed: {err}");
+                echo_request(&parts, &[])
+            }
+        };
+
+        // diagnostics and the echoed request are sent as separate parts so
+        // the echo can be typed message/http
+        let mut random = [0u8; 12];
+        let _ = self.shared.crypto_provider.secure_random.fill(&mut random);
+        let boundary: String = random.iter().map(|byte| format!("{byte:02x}")).collect();
+        let mut multipart = format!(
+            "--{boundary}\r\ncontent-type: text/plain; charset=utf-8\r\n\r\n{out}\r\n\
+             --{boundary}\r\ncontent-type: message/http\r\n\r\n"
+        )
+        .into_bytes();
+        multipart.extend_from_slice(&echo);
+        multipart.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+
+        let mut response = text_response(StatusCode::OK, Bytes::from(multipart));
+        let content_type = format!("multipart/mixed; boundary={boundary}");
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_str(&content_type).expect("boundary is hex"),
+        );
+        response
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::{Method, Request};
+
+    use super::{echo_request, is_diagnostic_request};
+    use crate::target::{Host, Target};
+    use crate::upstream::UpstreamRoute;
+
+    #[test]
+    fn diagnostic_requests() {
+        let trace = Request::builder()
+            .method(Method::TRACE)
+            .uri("/")
+            .body(())
+            .unwrap();
+        assert!(is_diagnostic_request(&trace, None));
+
+        let absolute = Request::get("http://RS-MITM.local/").body(()).unwrap();
+        assert!(is_diagnostic_request(&absolute, None));
+
+        let host_header = Request::get("/")
+            .header("host", "rs-mitm.local:8080")
+            .body(())
+            .unwrap();
+        assert!(is_diagnostic_request(&host_header, None));
+
+        let tunneled = Request::get("/").body(()).unwrap();
+        let route = UpstreamRoute {
+            target: Target {
+                host: Host::parse("rs-mitm.local"),
+                port: 443,
+            },
+            tls: true,
+            server_name: None,
+        };
+        assert!(is_diagnostic_request(&tunneled, Some(&route)));
+
+        let normal = Request::get("http://example.com/").body(()).unwrap();
+        assert!(!is_diagnostic_request(&normal, None));
+    }
+
+    #[test]
+    fn redact_credentials() {
+        let request = Request::builder()
+            .method(Method::TRACE)
+            .uri("/path")
+            .header("accept", "*/*")
+            .header("cookie", "session=secret")
+            .header("authorization", "Bearer secret")
+            .header("proxy-authorization", "Basic secret")
+            .body(())
+            .unwrap();
+        let (parts, ()) = request.into_parts();
+        assert_eq!(
+            echo_request(&parts, b""),
+            b"TRACE /path HTTP/1.1\r\n\
+              accept: */*\r\n\
+              cookie: <redacted>\r\n\
+              authorization: <redacted>\r\n\
+              proxy-authorization: <redacted>\r\n\r\n"
+        );
+    }
+}
@@ -0,0 +1,299 @@
+//! Flow records
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use async_channel::{Receiver, Sender, TrySendError};
+use bytes::Bytes;
+use hyper::{HeaderMap, Method, StatusCode, Uri, Version};
+use time::OffsetDateTime;
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+
+use crate::capture::CapturedBody;
+use crate::target::Target;
+
+/// Client connection received through a proxy which sent a PROXY protocol
+/// header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxiedConnection {
+    /// Address of the proxy, the client address it reported is used as
+    /// `peer_addr`
+    pub proxy_addr: SocketAddr,
+    /// Address the client connected to, as reported by the proxy
+    pub destination: SocketAddr,
+}
+
+/// Connection relayed to upstream without interception
+#[derive(Debug, Clone)]
+pub struct PassthroughFlow {
+    pub peer_addr: SocketAddr,
+    pub proxied: Option<ProxiedConnection>,
+    pub target: Target,
+    /// SNI hostname from the ClientHello
+    pub server_name: Option<String>,
+    pub started_at: OffsetDateTime,
+    pub duration: Duration,
+    /// Bytes sent from client to upstream
+    pub bytes_sent: u64,
+    /// Bytes sent from upstream to client
+    pub bytes_received: u64,
+    pub error: Option<String>,
+}
+
+/// Request as received from the client
+#[derive(Debug, Clone)]
+pub struct HttpRequestRecord {
+    pub method: Method,
+    pub uri: Uri,
+    pub version: Version,
+    pub headers: HeaderMap,
+    pub body: CapturedBody,
+}
+
+/// Response as received from upstream
+#[derive(Debug, Clone)]
+pub struct HttpResponseRecord {
+    pub status: StatusCode,
+    pub version: Version,
+    pub headers: HeaderMap,
+    pub body: CapturedBody,
+}
+
+/// Direction traffic was relayed in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ClientToUpstream,
+    UpstreamToClient,
+}
+
+/// WebSocket message opcode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebSocketOpcode {
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+    /// Opcode reserved for future use
+    Reserved(u8),
+}
+
+impl From<u8> for WebSocketOpcode {
+    fn from(opcode: u8) -> Self {
+        match opcode {
+            0x1 => WebSocketOpcode::Text,
+            0x2 => WebSocketOpcode::Binary,
+            0x8 => WebSocketOpcode::Close,
+            0x9 => WebSocketOpcode::Ping,
+            0xa => WebSocketOpcode::Pong,
+            other => WebSocketOpcode::Reserved(other),
+        }
+    }
+}
+
+/// WebSocket message, reassembled from its frames
+#[derive(Debug, Clone)]
+pub struct WebSocketMessage {
+    pub direction: Direction,
+    pub opcode: WebSocketOpcode,
+    /// Time the first frame of the message started arriving
+    pub timestamp: OffsetDateTime,
+    /// Up to the capture limit of the payload, decompressed if the message
+    /// was compressed
+    pub payload: Bytes,
+    /// Total length of the payload
+    pub length: u64,
+    /// Whether the message was compressed with permessage-deflate
+    pub compressed: bool,
+    /// Why the payload could not be captured in full, if it could not
+    pub error: Option<String>,
+}
+
+impl WebSocketMessage {
+    /// Whether payload past the capture limit was discarded
+    pub fn is_truncated(&self) -> bool {
+        self.length > self.payload.len() as u64
+    }
+}
+
+/// WebSocket connection relayed after an upgrade
+#[derive(Debug, Clone, Default)]
+pub struct WebSocketSession {
+    pub messages: Vec<WebSocketMessage>,
+    /// Messages not recorded because the capture budget was used up
+    pub dropped_messages: u64,
+    /// Bytes sent from client to upstream
+    pub bytes_sent: u64,
+    /// Bytes sent from upstream to client
+    pub bytes_received: u64,
+    pub error: Option<String>,
+}
+
+/// Chunk of a raw stream, as read from one side
+#[derive(Debug, Clone)]
+pub struct RawChunk {
+    pub direction: Direction,
+    /// Time the chunk was read
+    pub timestamp: OffsetDateTime,
+    pub data: Bytes,
+}
+
+/// Connection in an unrecognized protocol, relayed with its bytes captured
+#[derive(Debug, Clone)]
+pub struct RawStreamFlow {
+    pub peer_addr: SocketAddr,
+    pub proxied: Option<ProxiedConnection>,
+    pub target: Target,
+    pub started_at: OffsetDateTime,
+    pub duration: Duration,
+    /// Bytes sent from client to upstream
+    pub bytes_sent: u64,
+    /// Bytes sent from upstream to client
+    pub bytes_received: u64,
+    /// Up to the capture limit of each direction, in the order it was read
+    pub chunks: Vec<RawChunk>,
+    pub error: Option<String>,
+}
+
+impl RawStreamFlow {
+    /// Whether data past the capture limit was discarded
+    pub fn is_truncated(&self) -> bool {
+        let captured: usize = self.chunks.iter().map(|chunk| chunk.data.len()).sum();
+        self.bytes_sent + self.bytes_received > captured as u64
+    }
+}
+
+/// Intercepted HTTP request and response
+#[derive(Debug, Clone)]
+pub struct HttpFlow {
+    pub peer_addr: SocketAddr,
+    pub proxied: Option<ProxiedConnection>,
+    pub target: Target,
+    /// Whether upstream was connected to over TLS
+    pub tls: bool,
+    pub request: HttpRequestRecord,
+    /// Response, if upstream sent one
+    pub response: Option<HttpResponseRecord>,
+    pub started_at: OffsetDateTime,
+    /// Time until response headers were received
+    pub response_time: Option<Duration>,
+    /// Time until the response body was finished, or the WebSocket closed
+    pub duration: Duration,
+    /// Connection following a WebSocket upgrade
+    pub websocket: Option<WebSocketSession>,
+    pub error: Option<String>,
+}
+
+/// HTTP flow waiting for its bodies to be captured
+pub struct PendingHttpFlow {
+    /// Flow with bodies not filled in yet
+    pub flow: HttpFlow,
+    /// Time the request was received
+    pub start: Instant,
+    pub request_body: oneshot::Receiver<CapturedBody>,
+    pub response_body: Option<oneshot::Receiver<CapturedBody>>,
+    /// Resolves once a WebSocket connection following an upgrade closes
+    pub websocket: Option<oneshot::Receiver<WebSocketSession>>,
+}
+
+impl PendingHttpFlow {
+    /// Record the flow once both bodies have been captured
+    pub fn record_when_complete(self, recorder: &FlowRecorder) {
+        let recorder = recorder.clone();
+        tokio::spawn(async move {
+            let mut flow = self.flow;
+            flow.request.body = self.request_body.await.unwrap_or_default();
+            if let Some(response_body) = self.response_body
+                && let Some(response) = &mut flow.response
+            {
+                response.body = response_body.await.unwrap_or_default();
+            }
+            if let Some(websocket) = self.websocket {
+                flow.websocket = Some(websocket.await.unwrap_or_default());
+            }
+            flow.duration = self.start.elapsed();
+            recorder.record(FlowRecord::Http(Box::new(flow)));
+        });
+    }
+}
+
+/// Record of intercepted or relayed traffic
+#[derive(Debug, Clone)]
+pub enum FlowRecord {
+    Passthrough(Box<PassthroughFlow>),
+    Http(Box<HttpFlow>),
+    RawStream(Box<RawStreamFlow>),
+}
+
+/// Sends flow records to a consumer
+///
+/// Records are dropped if the consumer falls too far behind.
+#[derive(Clone)]
+pub struct FlowRecorder {
+    sender: Sender<FlowRecord>,
+}
+
+impl FlowRecorder {
+    pub fn new(capacity: usize) -> (Self, Receiver<FlowRecord>) {
+        let (sender, receiver) = async_channel::bounded(capacity);
+        (FlowRecorder { sender }, receiver)
+    }
+
+    pub fn record(&self, record: FlowRecord) {
+        match self.sender.try_send(record) {
+            Ok(()) | Err(TrySendError::Closed(_)) => {}
+            Err(TrySendError::Full(_)) => warn!("flow record consumer is behind, dropping record"),
+        }
+    }
+}
+
+/// Log flow records as they arrive
+pub async fn log_flows(receiver: Receiver<FlowRecord>) {
+    while let Ok(record) = receiver.recv().await {
+        match record {
+            FlowRecord::Passthrough(flow) => info!(
+                peer_addr = %flow.peer_addr,
+                proxy_addr = ?flow.proxied.map(|proxied| proxied.proxy_addr),
+                target = %flow.target,
+                server_name = ?flow.server_name,
+                started_at = %flow.started_at,
+                duration = ?flow.duration,
+                bytes_sent = flow.bytes_sent,
+                bytes_received = flow.bytes_received,
+                error = ?flow.error,
+                "passthrough flow"
+            ),
+            FlowRecord::Http(flow) => info!(
+                peer_addr = %flow.peer_addr,
+                proxy_addr = ?flow.proxied.map(|proxied| proxied.proxy_addr),
+                target = %flow.target,
+                tls = flow.tls,
+                method = %flow.request.method,
+                uri = %flow.request.uri,
+                status = flow.response.as_ref().map(|response| response.status.as_u16()),
+                request_length = flow.request.body.length,
+                response_length = flow.response.as_ref().map(|response| response.body.length),
+                response_time = ?flow.response_time,
+                duration = ?flow.duration,
+                websocket_messages = flow.websocket.as_ref().map(|websocket| websocket.messages.len()),
+                websocket_dropped = flow.websocket.as_ref().map(|websocket| websocket.dropped_messages),
+                error = ?flow.error,
+                "http flow"
+            ),
+            FlowRecord::RawStream(flow) => info!(
+                peer_addr = %flow.peer_addr,
+                proxy_addr = ?flow.proxied.map(|proxied| proxied.proxy_addr),
+                target = %flow.target,
+                started_at = %flow.started_at,
+                duration = ?flow.duration,
+                bytes_sent = flow.bytes_sent,
+                bytes_received = flow.bytes_received,
+                chunks = flow.chunks.len(),
+                truncated = flow.is_truncated(),
+                error = ?flow.error,
+                "raw stream flow"
+            ),
+        }
+    }
+}
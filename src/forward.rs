@@ -0,0 +1,348 @@
+//! Forwarding intercepted requests upstream
+
+use std::future::Future;
+use std::time::Instant;
+
+use http_body_util::BodyExt;
+use hyper::body::Incoming;
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+use hyper::http::uri::{PathAndQuery, Scheme};
+use hyper::{Method, Request, Response, StatusCode, Uri, Version};
+use hyper_util::rt::TokioIo;
+use time::OffsetDateTime;
+use tokio::sync::oneshot;
+use tracing::debug;
+
+use crate::capture::{CaptureBody, CapturedBody};
+use crate::flow::{HttpFlow, HttpRequestRecord, HttpResponseRecord, PendingHttpFlow};
+use crate::http1::ProxyBody;
+use crate::server::ConnectionHandler;
+use crate::target::Target;
+use crate::upstream::UpstreamRoute;
+use crate::websocket::{self, CaptureLimits, DeflateParams, UpgradeKind};
+
+/// Whether a comma separated header contains `token`
+pub fn has_token(headers: &HeaderMap, name: &HeaderName, token: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+/// Remove headers which only apply to a single connection
+///
+/// `Proxy-*` headers are addressed to us rather than upstream, so are removed
+/// too. `TE: trailers` is kept since it is needed by gRPC.
+pub fn remove_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    let proxy: Vec<HeaderName> = headers
+        .keys()
+        .filter(|name| name.as_str().starts_with("proxy-"))
+        .cloned()
+        .collect();
+    for name in listed.into_iter().chain(proxy) {
+        headers.remove(name);
+    }
+
+    for name in [
+        header::CONNECTION,
+        header::TRANSFER_ENCODING,
+        header::UPGRADE,
+        HeaderName::from_static("keep-alive"),
+    ] {
+        headers.remove(name);
+    }
+    if headers
+        .get(header::TE)
+        .is_some_and(|te| te.as_bytes() != b"trailers")
+    {
+        headers.remove(header::TE);
+    }
+}
+
+/// Add a `Via` entry for a message received with `version`
+pub fn append_via(headers: &mut HeaderMap, version: Version, pseudonym: &str) {
+    let protocol = match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    };
+    if let Ok(via) = HeaderValue::from_str(&format!("{protocol} {pseudonym}")) {
+        headers.append(header::VIA, via);
+    }
+}
+
+/// Convert a request to the form sent on HTTP/1.1 connections
+///
+/// The authority moves from the URI to the `Host` header, replacing any
+/// `Host` the client sent as required for absolute-form requests. Cookies
+/// split across several HTTP/2 headers are joined again.
+pub fn to_origin_form<B>(request: &mut Request<B>) {
+    if let Some(authority) = request.uri().authority()
+        && let Ok(host) = HeaderValue::from_str(authority.as_str())
+    {
+        request.headers_mut().insert(header::HOST, host);
+    }
+    if request.method() != Method::CONNECT {
+        let path = request
+            .uri()
+            .path_and_query()
+            .cloned()
+            .unwrap_or_else(|| PathAndQuery::from_static("/"));
+        *request.uri_mut() = Uri::from(path);
+    }
+
+    let cookies: Vec<&[u8]> = request
+        .headers()
+        .get_all(header::COOKIE)
+        .iter()
+        .map(HeaderValue::as_bytes)
+        .collect();
+    if cookies.len() > 1
+        && let Ok(joined) = HeaderValue::from_bytes(&cookies.join(&b"; "[..]))
+    {
+        request.headers_mut().insert(header::COOKIE, joined);
+    }
+    *request.version_mut() = Version::HTTP_11;
+}
+
+/// Convert a request to the form sent on HTTP/2 connections
+///
+/// The authority is taken from the `Host` header, or `target` if there is
+/// none.
+pub fn to_absolute_form<B>(request: &mut Request<B>, scheme: Scheme, target: &Target) {
+    let host = request.headers_mut().remove(header::HOST);
+    let authority = host
+        .as_ref()
+        .and_then(|host| host.to_str().ok())
+        .map(str::to_owned)
+        .unwrap_or_else(|| target.to_string());
+    let mut parts = request.uri().clone().into_parts();
+    if parts.authority.is_none() {
+        parts.scheme = Some(scheme);
+        parts.authority = authority.parse().ok();
+        if parts.path_and_query.is_none() {
+            parts.path_and_query = Some(PathAndQuery::from_static("/"));
+        }
+    }
+    if let Ok(uri) = Uri::from_parts(parts) {
+        *request.uri_mut() = uri;
+    }
+    *request.version_mut() = Version::HTTP_2;
+}
+
+impl ConnectionHandler {
+    /// Forward a request upstream with `send` and record the exchange
+    ///
+    /// The flow is recorded once both bodies have been streamed through, or
+    /// immediately if sending the request failed. WebSocket upgrades are
+    /// relayed once both sides have switched protocols, and recorded when the
+    /// WebSocket closes.
+    pub(crate) async fn forward_request<F, Fut>(
+        &self,
+        mut request: Request<Incoming>,
+        route: &UpstreamRoute,
+        send: F,
+    ) -> eyre::Result<Response<ProxyBody>>
+    where
+        F: FnOnce(Request<ProxyBody>) -> Fut,
+        Fut: Future<Output = eyre::Result<Response<Incoming>>>,
+    {
+        let started_at = OffsetDateTime::now_utc();
+        let start = Instant::now();
+        let limit = self.config.max_body_capture;
+
+        let websocket = UpgradeKind::of(&request);
+        let client_upgrade = websocket.map(|_| hyper::upgrade::on(&mut request));
+        let (mut parts, body) = request.into_parts();
+        let request_record = HttpRequestRecord {
+            method: parts.method.clone(),
+            uri: parts.uri.clone(),
+            version: parts.version,
+            headers: parts.headers.clone(),
+            body: CapturedBody::default(),
+        };
+        let (body, request_body) = CaptureBody::new(body, limit);
+        remove_hop_by_hop(&mut parts.headers);
+        // hyper already answered this when the body was first read
+        if parts
+            .headers
+            .get(header::EXPECT)
+            .is_some_and(|expect| expect.as_bytes().eq_ignore_ascii_case(b"100-continue"))
+        {
+            parts.headers.remove(header::EXPECT);
+        }
+        if let Some(kind) = websocket {
+            let mut key = [0u8; 16];
+            self.shared
+                .crypto_provider
+                .secure_random
+                .fill(&mut key)
+                .map_err(|_| eyre::eyre!("failed to generate WebSocket key"))?;
+            websocket::prepare_request(&mut parts, kind, key);
+        }
+        if let Some(pseudonym) = &self.shared.via {
+            append_via(&mut parts.headers, parts.version, pseudonym);
+        }
+        let request = Request::from_parts(parts, body.boxed());
+
+        let mut pending = PendingHttpFlow {
+            flow: HttpFlow {
+                peer_addr: self.peer_addr,
+                proxied: self.proxied,
+                target: route.target.clone(),
+                tls: route.tls,
+                request: request_record,
+                response: None,
+                started_at,
+                response_time: None,
+                duration: Default::default(),
+                websocket: None,
+                error: None,
+            },
+            start,
+            request_body,
+            response_body: None,
+            websocket: None,
+        };
+
+        let mut response = match send(request).await {
+            Ok(response) => response,
+            Err(err) => {
+                debug!(?err, peer_addr = %self.peer_addr, target = %route.target, "upstream request failed");
+                pending.flow.error = Some(format!("{err:#}"));
+                pending.record_when_complete(&self.shared.flows);
+                return Err(err);
+            }
+        };
+
+        let upgrade = match (websocket, client_upgrade) {
+            (Some(kind), Some(client_upgrade))
+                if response.status() == StatusCode::SWITCHING_PROTOCOLS =>
+            {
+                Some((kind, client_upgrade, hyper::upgrade::on(&mut response)))
+            }
+            _ => None,
+        };
+
+        let (mut parts, body) = response.into_parts();
+        let (body, response_body) = CaptureBody::new(body, limit);
+        pending.flow.response_time = Some(start.elapsed());
+        pending.flow.response = Some(HttpResponseRecord {
+            status: parts.status,
+            version: parts.version,
+            headers: parts.headers.clone(),
+            body: CapturedBody::default(),
+        });
+        pending.response_body = Some(response_body);
+        let mut upgraded = None;
+        if let Some((kind, client_upgrade, upstream_upgrade)) = upgrade {
+            let deflate = DeflateParams::from_response_headers(&parts.headers);
+            let limits = CaptureLimits {
+                message_bytes: limit,
+                session_bytes: self.config.max_websocket_capture,
+                messages: self.config.max_websocket_messages,
+            };
+            let (sender, receiver) = oneshot::channel();
+            pending.websocket = Some(receiver);
+            let peer_addr = self.peer_addr;
+            tokio::spawn(async move {
+                match tokio::try_join!(client_upgrade, upstream_upgrade) {
+                    Ok((client, upstream)) => {
+                        let client = TokioIo::new(client);
+                        let upstream = TokioIo::new(upstream);
+                        let session =
+                            websocket::relay_websocket(client, upstream, deflate, limits).await;
+                        // the flow may no longer be waited on
+                        let _ = sender.send(session);
+                    }
+                    Err(err) => debug!(?err, %peer_addr, "WebSocket upgrade failed"),
+                }
+            });
+            upgraded = Some(kind);
+        }
+        pending.record_when_complete(&self.shared.flows);
+
+        remove_hop_by_hop(&mut parts.headers);
+        if let Some(kind) = upgraded {
+            websocket::prepare_response(&mut parts, kind);
+        }
+        if let Some(pseudonym) = &self.shared.via {
+            append_via(&mut parts.headers, parts.version, pseudonym);
+        }
+        Ok(Response::from_parts(parts, body.boxed()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::HeaderMap;
+    use hyper::http::uri::Scheme;
+    use hyper::{Request, Version};
+
+    use super::{append_via, remove_hop_by_hop, to_absolute_form, to_origin_form};
+    use crate::target::{Host, Target};
+
+    #[test]
+    fn strip_hop_by_hop() {
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            ("connection", "close, x-custom"),
+            ("x-custom", "1"),
+            ("proxy-authorization", "Basic Zm9vOmJhcg=="),
+            ("proxy-connection", "keep-alive"),
+            ("te", "trailers"),
+            ("accept", "*/*"),
+        ] {
+            headers.insert(name, value.parse().unwrap());
+        }
+        remove_hop_by_hop(&mut headers);
+        let mut names: Vec<&str> = headers.keys().map(|name| name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, ["accept", "te"]);
+
+        append_via(&mut headers, Version::HTTP_11, "rs-mitm");
+        append_via(&mut headers, Version::HTTP_2, "rs-mitm");
+        let via: Vec<_> = headers.get_all("via").iter().collect();
+        assert_eq!(via, ["1.1 rs-mitm", "2 rs-mitm"]);
+    }
+
+    #[test]
+    fn request_forms() {
+        let mut request = Request::get("http://example.com:8080/a?b")
+            .version(Version::HTTP_2)
+            .header("host", "ignored.test")
+            .header("cookie", "a=1")
+            .header("cookie", "b=2")
+            .body(())
+            .unwrap();
+        to_origin_form(&mut request);
+        assert_eq!(request.uri(), "/a?b");
+        assert_eq!(request.version(), Version::HTTP_11);
+        assert_eq!(request.headers()["host"], "example.com:8080");
+        assert_eq!(request.headers()["cookie"], "a=1; b=2");
+
+        let target = Target {
+            host: Host::Name("upstream.test".to_owned()),
+            port: 80,
+        };
+        to_absolute_form(&mut request, Scheme::HTTP, &target);
+        assert_eq!(request.uri(), "http://example.com:8080/a?b");
+        assert_eq!(request.version(), Version::HTTP_2);
+        assert!(!request.headers().contains_key("host"));
+
+        let mut request = Request::get("/").body(()).unwrap();
+        to_absolute_form(&mut request, Scheme::HTTP, &target);
+        assert_eq!(request.uri(), "http://upstream.test:80/");
+    }
+}
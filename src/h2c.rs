@@ -0,0 +1,378 @@
+//! Cleartext HTTP/2
+//!
+//! Clients may switch an HTTP/1.1 connection to HTTP/2 with `Upgrade: h2c`.
+//! hyper's HTTP/2 server cannot take over a connection mid-request, so the
+//! upgrade request is encoded as a HEADERS frame for stream 1 and inserted
+//! after the client's connection preface, where it is served like any other
+//! stream. Settings from `HTTP2-Settings` are merged into the client's first
+//! SETTINGS frame, which keeps the single acknowledgement the client expects.
+
+use std::future::Future;
+use std::pin::Pin;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use eyre::Context as _;
+use hyper::body::{Body, Incoming};
+use hyper::header::{self, HeaderName, HeaderValue};
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use tokio::io::AsyncReadExt;
+use tracing::debug;
+
+use crate::common::AsyncStream;
+use crate::forward::{has_token, remove_hop_by_hop};
+use crate::http1::{ProxyBody, empty_body};
+use crate::http2::StreamUpstream;
+use crate::replay_buffer::ReplayBuffer;
+use crate::server::ConnectionHandler;
+use crate::upstream::UpstreamRoute;
+
+/// Client connection preface
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const FRAME_HEADER_LENGTH: usize = 9;
+const FRAME_HEADERS: u8 = 0x1;
+const FRAME_SETTINGS: u8 = 0x4;
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+const SETTING_LENGTH: usize = 6;
+/// Initial SETTINGS_MAX_FRAME_SIZE, which the server has not yet had a chance
+/// to raise
+const MAX_FRAME_SIZE: usize = 16384;
+
+static HTTP2_SETTINGS: HeaderName = HeaderName::from_static("http2-settings");
+
+/// Request to switch an HTTP/1.1 connection to h2c
+#[derive(Debug)]
+pub struct H2cUpgrade {
+    /// SETTINGS frame payload from `HTTP2-Settings`
+    settings: Vec<u8>,
+    /// HPACK encoded request headers for stream 1
+    header_block: Vec<u8>,
+}
+
+impl H2cUpgrade {
+    /// Parse an upgrade from `request`
+    ///
+    /// Returns `None` if the request did not ask to upgrade, or asked in a way
+    /// that cannot be honoured, in which case it should be handled as plain
+    /// HTTP/1.1. Requests with bodies are not upgraded, since the body would
+    /// have to be read before switching protocols.
+    pub fn from_request<B: Body>(request: &Request<B>) -> Option<Self> {
+        let headers = request.headers();
+        if !has_token(headers, &header::UPGRADE, "h2c")
+            || !has_token(headers, &header::CONNECTION, "upgrade")
+            || !has_token(headers, &header::CONNECTION, "http2-settings")
+            || !request.body().is_end_stream()
+        {
+            return None;
+        }
+
+        let mut settings = headers.get_all(&HTTP2_SETTINGS).iter();
+        let (Some(encoded), None) = (settings.next(), settings.next()) else {
+            return None;
+        };
+        let encoded = encoded.to_str().ok()?.trim().trim_end_matches('=');
+        let settings = URL_SAFE_NO_PAD.decode(encoded).ok()?;
+        if !settings.len().is_multiple_of(SETTING_LENGTH) {
+            return None;
+        }
+
+        let header_block = encode_request_headers(request);
+        if header_block.len() > MAX_FRAME_SIZE {
+            return None;
+        }
+        Some(H2cUpgrade {
+            settings,
+            header_block,
+        })
+    }
+
+    /// Read the client's connection preface and first SETTINGS frame from
+    /// `stream`, and return a stream which replays them with the upgrade
+    /// applied
+    pub async fn accept<S: AsyncStream>(self, mut stream: S) -> eyre::Result<ReplayBuffer<S>> {
+        let mut preface = [0u8; PREFACE.len()];
+        stream
+            .read_exact(&mut preface)
+            .await
+            .wrap_err("failed to read connection preface")?;
+        if preface != PREFACE {
+            eyre::bail!("invalid HTTP/2 connection preface");
+        }
+
+        let mut frame_header = [0u8; FRAME_HEADER_LENGTH];
+        stream
+            .read_exact(&mut frame_header)
+            .await
+            .wrap_err("failed to read SETTINGS frame")?;
+        let length = u32::from_be_bytes([0, frame_header[0], frame_header[1], frame_header[2]]);
+        let length = length as usize;
+        if frame_header[3] != FRAME_SETTINGS
+            || frame_header[4] != 0
+            || frame_header[5..] != [0; 4]
+            || !length.is_multiple_of(SETTING_LENGTH)
+        {
+            eyre::bail!("connection preface not followed by SETTINGS frame");
+        }
+        if length > MAX_FRAME_SIZE {
+            eyre::bail!("SETTINGS frame too large");
+        }
+        let mut client_settings = vec![0u8; length];
+        stream
+            .read_exact(&mut client_settings)
+            .await
+            .wrap_err("failed to read SETTINGS frame")?;
+
+        Ok(ReplayBuffer::with_prefix(
+            stream,
+            self.rewrite_preface(&client_settings)?,
+        ))
+    }
+
+    /// Connection preface and first frames as the HTTP/2 server should see
+    /// them
+    fn rewrite_preface(&self, client_settings: &[u8]) -> eyre::Result<Vec<u8>> {
+        // later settings take precedence
+        let settings = [&self.settings[..], client_settings].concat();
+        if settings.len() > MAX_FRAME_SIZE {
+            eyre::bail!("SETTINGS frame too large");
+        }
+
+        let mut out = PREFACE.to_vec();
+        write_frame(&mut out, FRAME_SETTINGS, 0, 0, &settings);
+        write_frame(
+            &mut out,
+            FRAME_HEADERS,
+            FLAG_END_STREAM | FLAG_END_HEADERS,
+            1,
+            &self.header_block,
+        );
+        Ok(out)
+    }
+}
+
+fn write_frame(out: &mut Vec<u8>, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    out.push(kind);
+    out.push(flags);
+    out.extend_from_slice(&stream_id.to_be_bytes());
+    out.extend_from_slice(payload);
+}
+
+/// HPACK encode the pseudo-headers and headers of an upgrade request
+///
+/// Every field is a literal without indexing, so no dynamic table state is
+/// shared with the client's later header blocks.
+fn encode_request_headers<B>(request: &Request<B>) -> Vec<u8> {
+    let mut headers = request.headers().clone();
+    remove_hop_by_hop(&mut headers);
+    headers.remove(&HTTP2_SETTINGS);
+    // the authority of absolute-form requests takes precedence over Host
+    let host = headers.remove(header::HOST);
+    let authority = request
+        .uri()
+        .authority()
+        .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok())
+        .or(host);
+    let path = request
+        .uri()
+        .path_and_query()
+        .map_or("/", |path| path.as_str());
+
+    let mut block = Vec::new();
+    encode_field(&mut block, b":method", request.method().as_str().as_bytes());
+    encode_field(&mut block, b":scheme", b"http");
+    if let Some(authority) = &authority {
+        encode_field(&mut block, b":authority", authority.as_bytes());
+    }
+    encode_field(&mut block, b":path", path.as_bytes());
+    for (name, value) in &headers {
+        encode_field(&mut block, name.as_str().as_bytes(), value.as_bytes());
+    }
+    block
+}
+
+/// Literal header field without indexing, with a literal name
+fn encode_field(block: &mut Vec<u8>, name: &[u8], value: &[u8]) {
+    block.push(0);
+    encode_string(block, name);
+    encode_string(block, value);
+}
+
+/// String literal without Huffman coding
+fn encode_string(block: &mut Vec<u8>, value: &[u8]) {
+    encode_integer(block, value.len(), 7);
+    block.extend_from_slice(value);
+}
+
+fn encode_integer(block: &mut Vec<u8>, value: usize, prefix_bits: u32) {
+    let max_prefix = (1 << prefix_bits) - 1;
+    if value < max_prefix {
+        block.push(value as u8);
+        return;
+    }
+    block.push(max_prefix as u8);
+    let mut value = value - max_prefix;
+    while value >= 0x80 {
+        block.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+impl ConnectionHandler {
+    /// Whether `route` is a cleartext upstream configured to only speak
+    /// HTTP/2 with prior knowledge
+    pub(crate) fn is_h2c_only(&self, route: &UpstreamRoute) -> bool {
+        if route.tls {
+            return false;
+        }
+        let host = route.target.host.to_string();
+        self.shared
+            .h2c_upstreams
+            .iter()
+            .any(|pattern| pattern.matches(&host))
+    }
+
+    /// Switch an HTTP/1.1 connection to h2c and intercept it
+    pub(crate) fn handle_h2c_upgrade(
+        &self,
+        request: Request<Incoming>,
+        upgrade: H2cUpgrade,
+        route: UpstreamRoute,
+    ) -> Response<ProxyBody> {
+        debug!(peer_addr = %self.peer_addr, target = %route.target, "upgrading to h2c");
+
+        let handler = self.clone();
+        // boxed to break the handle_http1 -> handle_h2c -> handle_http2 cycle
+        let upgraded: Pin<Box<dyn Future<Output = ()> + Send>> = Box::pin(async move {
+            let upgraded = match hyper::upgrade::on(request).await {
+                Ok(upgraded) => upgraded,
+                Err(err) => {
+                    debug!(?err, peer_addr = %handler.peer_addr, "h2c upgrade failed");
+                    return;
+                }
+            };
+            if let Err(err) = handler
+                .handle_h2c(TokioIo::new(upgraded), upgrade, route)
+                .await
+            {
+                debug!(?err, peer_addr = %handler.peer_addr, "h2c handler exited with error");
+            }
+        });
+        tokio::spawn(upgraded);
+
+        let mut response = Response::new(empty_body());
+        *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+        let headers = response.headers_mut();
+        headers.insert(header::CONNECTION, HeaderValue::from_static("Upgrade"));
+        headers.insert(header::UPGRADE, HeaderValue::from_static("h2c"));
+        response
+    }
+
+    /// Serve a connection which upgraded to h2c
+    ///
+    /// Upstreams are only sent HTTP/2 if configured as h2c-only, since the
+    /// client upgrading says nothing about what upstream supports.
+    async fn handle_h2c(
+        &self,
+        stream: impl AsyncStream,
+        upgrade: H2cUpgrade,
+        route: UpstreamRoute,
+    ) -> eyre::Result<()> {
+        let stream = upgrade.accept(stream).await?;
+        if self.is_h2c_only(&route) {
+            self.handle_http2(stream, Some(route), None).await
+        } else {
+            self.serve_http2(stream, route, StreamUpstream::Http1, None)
+                .await
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+    use http_body_util::Empty;
+    use hyper::Request;
+
+    use super::{H2cUpgrade, PREFACE, encode_integer};
+
+    #[test]
+    fn hpack_integer() {
+        // examples from RFC 7541 appendix C.1
+        let mut block = Vec::new();
+        encode_integer(&mut block, 10, 5);
+        assert_eq!(block, [10]);
+
+        let mut block = Vec::new();
+        encode_integer(&mut block, 1337, 5);
+        assert_eq!(block, [31, 154, 10]);
+    }
+
+    #[test]
+    fn upgrade_preface() {
+        let request = Request::get("/index.html")
+            .header("host", "example.com")
+            .header("connection", "Upgrade, HTTP2-Settings")
+            .header("upgrade", "h2c")
+            // SETTINGS_ENABLE_PUSH = 0
+            .header("http2-settings", "AAIAAAAA")
+            .header("accept", "*/*")
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+        let upgrade = H2cUpgrade::from_request(&request).unwrap();
+        assert_eq!(upgrade.settings, [0, 2, 0, 0, 0, 0]);
+
+        // SETTINGS_MAX_CONCURRENT_STREAMS = 100
+        let client_settings = [0, 3, 0, 0, 0, 100];
+        let out = upgrade.rewrite_preface(&client_settings).unwrap();
+        let (preface, rest) = out.split_at(PREFACE.len());
+        assert_eq!(preface, PREFACE);
+        let (settings, headers) = rest.split_at(9 + 12);
+        assert_eq!(
+            settings,
+            [
+                0, 0, 12, 4, 0, 0, 0, 0, 0, // frame header
+                0, 2, 0, 0, 0, 0, 0, 3, 0, 0, 0, 100,
+            ]
+        );
+
+        let mut block = Vec::new();
+        for (name, value) in [
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":authority", "example.com"),
+            (":path", "/index.html"),
+            ("accept", "*/*"),
+        ] {
+            block.push(0);
+            block.push(name.len() as u8);
+            block.extend_from_slice(name.as_bytes());
+            block.push(value.len() as u8);
+            block.extend_from_slice(value.as_bytes());
+        }
+        assert_eq!(&headers[..9], [0, 0, block.len() as u8, 1, 5, 0, 0, 0, 1]);
+        assert_eq!(&headers[9..], block);
+    }
+
+    #[test]
+    fn upgrade_not_requested() {
+        let request = Request::get("/")
+            .header("upgrade", "h2c")
+            .header("http2-settings", "")
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+        assert!(H2cUpgrade::from_request(&request).is_none());
+
+        let request = Request::post("/")
+            .header("connection", "Upgrade, HTTP2-Settings")
+            .header("upgrade", "h2c")
+            .header("http2-settings", "")
+            .body("body".to_owned())
+            .unwrap();
+        assert!(H2cUpgrade::from_request(&request).is_none());
+    }
+}
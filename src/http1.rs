@@ -0,0 +1,358 @@
+//! HTTP/1 interception
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use eyre::Context;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::body::Incoming;
+use hyper::client::conn::http1::SendRequest;
+use hyper::client::conn::http2::SendRequest as Http2SendRequest;
+use hyper::ext::ReasonPhrase;
+use hyper::http::uri::Scheme;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio_rustls::client::TlsStream;
+use tracing::debug;
+
+use crate::common::AsyncStream;
+use crate::h2c::H2cUpgrade;
+use crate::http2::send_http2;
+use crate::server::ConnectionHandler;
+use crate::target::Target;
+use crate::tls::ALPN_HTTP1;
+use crate::upstream::UpstreamRoute;
+use crate::{diagnostics, forward};
+
+/// Most idle upstream connections kept by an [`Http1Pool`]
+const MAX_POOLED_CONNECTIONS: usize = 8;
+
+/// Body type of responses sent to clients
+pub type ProxyBody = BoxBody<Bytes, hyper::Error>;
+
+pub fn empty_body() -> ProxyBody {
+    Empty::new().map_err(|never| match never {}).boxed()
+}
+
+pub fn text_response(status: StatusCode, text: impl Into<Bytes>) -> Response<ProxyBody> {
+    let mut response = Response::new(
+        Full::new(text.into())
+            .map_err(|never| match never {})
+            .boxed(),
+    );
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    response
+}
+
+/// Upstream connection shared by requests on one client connection
+///
+/// Requests on an HTTP/1 connection are handled one at a time, so the
+/// connection is reused until upstream closes it or a request is routed
+/// elsewhere. Upstreams configured as h2c-only are sent requests over HTTP/2
+/// with prior knowledge instead.
+pub(crate) struct Http1Session {
+    /// Upstream for origin-form requests
+    route: Option<UpstreamRoute>,
+    sender: Mutex<Option<(UpstreamRoute, SendRequest<ProxyBody>)>>,
+    http2_sender: Mutex<Option<(UpstreamRoute, Http2SendRequest<ProxyBody>)>>,
+}
+
+impl Http1Session {
+    /// Send a request upstream, reconnecting if the previous connection was
+    /// closed
+    async fn send(
+        &self,
+        handler: &ConnectionHandler,
+        route: &UpstreamRoute,
+        mut request: Request<ProxyBody>,
+    ) -> eyre::Result<Response<Incoming>> {
+        if handler.is_h2c_only(route) {
+            return self.send_h2c(handler, route, request).await;
+        }
+
+        forward::to_origin_form(&mut request);
+        let mut sender = self.sender.lock().await;
+        let reusable = match sender.as_mut() {
+            Some((connected_route, sender)) => {
+                connected_route == route && sender.ready().await.is_ok()
+            }
+            None => false,
+        };
+        if !reusable {
+            let mut connected = handler.connect_http1(route).await?;
+            connected
+                .ready()
+                .await
+                .wrap_err("upstream connection closed")?;
+            *sender = Some((route.clone(), connected));
+        }
+
+        let (_, connected) = sender.as_mut().expect("sender is connected");
+        let response = connected.send_request(request);
+        drop(sender);
+        response.await.wrap_err("upstream request failed")
+    }
+
+    /// Send a request to an h2c-only upstream
+    async fn send_h2c(
+        &self,
+        handler: &ConnectionHandler,
+        route: &UpstreamRoute,
+        mut request: Request<ProxyBody>,
+    ) -> eyre::Result<Response<Incoming>> {
+        forward::to_absolute_form(&mut request, Scheme::HTTP, &route.target);
+        let mut sender = self.http2_sender.lock().await;
+        let reusable = match sender.as_mut() {
+            Some((connected_route, sender)) => {
+                connected_route == route && sender.ready().await.is_ok()
+            }
+            None => false,
+        };
+        if !reusable {
+            let connected = handler.connect_http2(route).await?.sender;
+            *sender = Some((route.clone(), connected));
+        }
+        let (_, sender) = sender.as_ref().expect("sender is connected");
+        let sender = sender.clone();
+        send_http2(sender, request).await
+    }
+}
+
+/// Upstream HTTP/1.1 connections shared by the streams of one HTTP/2 client
+/// connection
+///
+/// Each connection carries one request at a time, so a connection is only
+/// reused for a route once upstream's previous response has been read.
+#[derive(Default)]
+pub(crate) struct Http1Pool {
+    connections: Mutex<Vec<(UpstreamRoute, SendRequest<ProxyBody>)>>,
+}
+
+impl Http1Pool {
+    /// Send a request upstream on an idle connection, connecting if there is
+    /// none
+    pub(crate) async fn send(
+        &self,
+        handler: &ConnectionHandler,
+        route: &UpstreamRoute,
+        mut request: Request<ProxyBody>,
+    ) -> eyre::Result<Response<Incoming>> {
+        forward::to_origin_form(&mut request);
+        let idle = {
+            let mut connections = self.connections.lock().await;
+            connections.retain(|(_, sender)| !sender.is_closed());
+            connections
+                .iter()
+                .position(|(connected_route, sender)| connected_route == route && sender.is_ready())
+                .map(|index| connections.swap_remove(index).1)
+        };
+        let mut sender = match idle {
+            Some(sender) => sender,
+            None => {
+                let mut connected = handler.connect_http1(route).await?;
+                connected
+                    .ready()
+                    .await
+                    .wrap_err("upstream connection closed")?;
+                connected
+            }
+        };
+
+        let response = sender.send_request(request);
+        // pooled while the request is in flight, since it only becomes ready
+        // again once the response has been read
+        let mut connections = self.connections.lock().await;
+        if connections.len() < MAX_POOLED_CONNECTIONS {
+            connections.push((route.clone(), sender));
+        }
+        drop(connections);
+        response.await.wrap_err("upstream request failed")
+    }
+}
+
+impl ConnectionHandler {
+    /// Serve an HTTP/1 connection
+    ///
+    /// `route` is the upstream for origin-form requests, if known. `upstream`
+    /// is an already established connection to it.
+    pub(crate) async fn handle_http1(
+        &self,
+        stream: impl AsyncStream,
+        route: Option<UpstreamRoute>,
+        upstream: Option<TlsStream<TcpStream>>,
+    ) -> eyre::Result<()> {
+        let sender = match upstream {
+            Some(upstream) => match self.http1_handshake(upstream).await {
+                Ok(sender) => Some(sender),
+                Err(err) => {
+                    debug!(?err, peer_addr = %self.peer_addr, "failed to reuse upstream connection");
+                    None
+                }
+            },
+            None => None,
+        };
+        let sender = route.clone().zip(sender);
+        let session = Arc::new(Http1Session {
+            route,
+            sender: Mutex::new(sender),
+            http2_sender: Mutex::new(None),
+        });
+
+        let handler = self.clone();
+        let service = service_fn(move |request| {
+            let handler = handler.clone();
+            let session = Arc::clone(&session);
+            async move { Ok::<_, Infallible>(handler.handle_http1_request(request, &session).await) }
+        });
+
+        http1::Builder::new()
+            .serve_connection(TokioIo::new(stream), service)
+            .with_upgrades()
+            .await
+            .wrap_err("error serving HTTP/1 connection")
+    }
+
+    /// Answer the first request on an HTTP/1 connection with an error, then
+    /// close it
+    pub(crate) async fn serve_http1_error(
+        &self,
+        stream: impl AsyncStream,
+        status: StatusCode,
+        message: String,
+    ) -> eyre::Result<()> {
+        let service = service_fn(move |_request| {
+            let response = text_response(status, message.clone());
+            async move { Ok::<_, Infallible>(response) }
+        });
+
+        http1::Builder::new()
+            .keep_alive(false)
+            .serve_connection(TokioIo::new(stream), service)
+            .await
+            .wrap_err("error serving HTTP/1 connection")
+    }
+
+    async fn handle_http1_request(
+        &self,
+        request: Request<Incoming>,
+        session: &Http1Session,
+    ) -> Response<ProxyBody> {
+        if request.method() == Method::CONNECT {
+            return self.handle_connect(request);
+        }
+        if diagnostics::is_diagnostic_request(&request, session.route.as_ref()) {
+            return self
+                .diagnostic_response(request, session.route.as_ref())
+                .await;
+        }
+
+        // absolute-form requests come from clients using us as a proxy
+        let route = match UpstreamRoute::from_absolute_uri(request.uri()) {
+            Ok(Some(route)) => route,
+            Ok(None) => match &session.route {
+                Some(route) => route.clone(),
+                None => {
+                    return text_response(
+                        StatusCode::BAD_GATEWAY,
+                        "no upstream known for this request\n",
+                    );
+                }
+            },
+            Err(err) => return text_response(StatusCode::BAD_REQUEST, format!("{err:#}\n")),
+        };
+        // h2c is only defined for cleartext connections
+        if !route.tls
+            && let Some(upgrade) = H2cUpgrade::from_request(&request)
+        {
+            return self.handle_h2c_upgrade(request, upgrade, route);
+        }
+        let send = |request| session.send(self, &route, request);
+        match self.forward_request(request, &route, send).await {
+            Ok(response) => response,
+            Err(err) => text_response(StatusCode::BAD_GATEWAY, format!("{err:#}\n")),
+        }
+    }
+
+    /// Connect to upstream for forwarding HTTP/1 requests
+    async fn connect_http1(&self, route: &UpstreamRoute) -> eyre::Result<SendRequest<ProxyBody>> {
+        if route.tls {
+            let stream = self
+                .connect_upstream_tls(
+                    &route.target,
+                    route.server_name.as_deref(),
+                    &[ALPN_HTTP1.to_vec()],
+                )
+                .await?;
+            self.http1_handshake(stream).await
+        } else {
+            let stream = self
+                .connect_upstream(&route.target)
+                .await
+                .wrap_err_with(|| format!("failed to connect to {}", route.target))?;
+            self.http1_handshake(stream).await
+        }
+    }
+
+    /// Start an HTTP/1 client connection over `stream`
+    async fn http1_handshake(
+        &self,
+        stream: impl AsyncStream,
+    ) -> eyre::Result<SendRequest<ProxyBody>> {
+        let (sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+            .await
+            .wrap_err("upstream HTTP/1 handshake failed")?;
+        let peer_addr = self.peer_addr;
+        tokio::spawn(async move {
+            if let Err(err) = connection.with_upgrades().await {
+                debug!(?err, %peer_addr, "upstream HTTP/1 connection failed");
+            }
+        });
+        Ok(sender)
+    }
+
+    /// Accept a CONNECT request and intercept the tunneled stream
+    fn handle_connect(&self, request: Request<Incoming>) -> Response<ProxyBody> {
+        let Some(authority) = request.uri().authority() else {
+            return text_response(
+                StatusCode::BAD_REQUEST,
+                "CONNECT target must be host:port\n",
+            );
+        };
+        let target = Target::from_authority(authority, 443);
+        debug!(peer_addr = %self.peer_addr, %target, "CONNECT");
+
+        let handler = self.clone();
+        // boxed to break the handle_http1 -> handle_tunnel -> handle_http1 cycle
+        let tunnel: Pin<Box<dyn Future<Output = ()> + Send>> = Box::pin(async move {
+            let upgraded = match hyper::upgrade::on(request).await {
+                Ok(upgraded) => upgraded,
+                Err(err) => {
+                    debug!(?err, peer_addr = %handler.peer_addr, "CONNECT upgrade failed");
+                    return;
+                }
+            };
+            if let Err(err) = handler.handle_tunnel(TokioIo::new(upgraded), target).await {
+                debug!(?err, peer_addr = %handler.peer_addr, "tunnel handler exited with error");
+            }
+        });
+        tokio::spawn(tunnel);
+
+        let mut response = Response::new(empty_body());
+        response
+            .extensions_mut()
+            .insert(ReasonPhrase::from_static(b"Connection Established"));
+        response
+    }
+}
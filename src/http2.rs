@@ -0,0 +1,283 @@
+//! HTTP/2 interception
+//!
+//! Each client connection is paired with a single upstream connection, and
+//! each stream is forwarded as a separate request on it. Streams from clients
+//! which upgraded to h2c are instead forwarded over pooled HTTP/1.1
+//! connections, unless upstream is configured as h2c-only. Stream resets are
+//! propagated in both directions by hyper when a body errors or is dropped,
+//! and a GOAWAY from upstream gracefully shuts down the client connection.
+//! Server push is disabled by hyper's client, so upstream can never push.
+
+use std::convert::Infallible;
+use std::error::Error;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use eyre::Context;
+use hyper::body::Incoming;
+use hyper::client::conn::http2::SendRequest;
+use hyper::server::conn::http2;
+use hyper::service::{Service, service_fn};
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio_rustls::client::TlsStream;
+use tracing::{debug, warn};
+
+use crate::common::AsyncStream;
+use crate::diagnostics;
+use crate::http1::{Http1Pool, ProxyBody, text_response};
+use crate::server::ConnectionHandler;
+use crate::tls::ALPN_HTTP2;
+use crate::upstream::UpstreamRoute;
+use crate::websocket::UpgradeKind;
+
+type ResponseFuture =
+    Pin<Box<dyn Future<Output = Result<Response<ProxyBody>, Box<dyn Error + Send + Sync>>> + Send>>;
+
+/// Where streams are forwarded to
+#[derive(Clone)]
+pub(crate) enum StreamUpstream {
+    /// Streams share one upstream HTTP/2 connection
+    Http2(SendRequest<ProxyBody>),
+    /// Streams are sent over HTTP/1.1 connections from the client
+    /// connection's pool
+    Http1,
+}
+
+/// Forwards streams on one client connection
+///
+/// A named service rather than `service_fn`, since rustc fails to prove the
+/// executor bounds for the closure's future.
+#[derive(Clone)]
+struct Http2Service {
+    handler: ConnectionHandler,
+    route: UpstreamRoute,
+    upstream: StreamUpstream,
+    /// Connections for streams sent over HTTP/1.1, including WebSockets
+    http1_pool: Arc<Http1Pool>,
+}
+
+impl Service<Request<Incoming>> for Http2Service {
+    type Response = Response<ProxyBody>;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = ResponseFuture;
+
+    fn call(&self, request: Request<Incoming>) -> Self::Future {
+        let service = self.clone();
+        Box::pin(async move {
+            service
+                .handler
+                .handle_http2_request(
+                    request,
+                    &service.route,
+                    service.upstream,
+                    &service.http1_pool,
+                )
+                .await
+        })
+    }
+}
+
+/// Upstream HTTP/2 connection
+pub(crate) struct Http2Upstream {
+    pub sender: SendRequest<ProxyBody>,
+    /// Resolves when the upstream connection has closed
+    pub closed: oneshot::Receiver<()>,
+}
+
+pub(crate) async fn send_http2(
+    mut sender: SendRequest<ProxyBody>,
+    request: Request<ProxyBody>,
+) -> eyre::Result<Response<Incoming>> {
+    sender
+        .ready()
+        .await
+        .wrap_err("upstream connection closed")?;
+    sender
+        .send_request(request)
+        .await
+        .wrap_err("upstream request failed")
+}
+
+impl ConnectionHandler {
+    /// Serve an HTTP/2 connection
+    ///
+    /// `route` is the upstream to forward streams to, if known. `upstream` is
+    /// an already established connection to it which negotiated h2.
+    pub(crate) async fn handle_http2(
+        &self,
+        stream: impl AsyncStream,
+        route: Option<UpstreamRoute>,
+        upstream: Option<TlsStream<TcpStream>>,
+    ) -> eyre::Result<()> {
+        let Some(route) = route else {
+            let message = "no upstream known for this request\n".to_owned();
+            return self
+                .serve_http2_error(stream, StatusCode::BAD_GATEWAY, message)
+                .await;
+        };
+        // the diagnostic host has no upstream, streams are answered locally
+        if diagnostics::is_diagnostic_host(&route.target.host) {
+            return self
+                .serve_http2(stream, route, StreamUpstream::Http1, None)
+                .await;
+        }
+        let connected = match upstream {
+            Some(upstream) => self.http2_handshake(upstream).await,
+            None => self.connect_http2(&route).await,
+        };
+        let upstream = match connected {
+            Ok(upstream) => upstream,
+            Err(err) => {
+                warn!(peer_addr = %self.peer_addr, target = %route.target, ?err, "upstream HTTP/2 connection failed");
+                let message = format!("{err:#}\n");
+                return self
+                    .serve_http2_error(stream, StatusCode::BAD_GATEWAY, message)
+                    .await;
+            }
+        };
+
+        let upstream_closed = upstream.closed;
+        self.serve_http2(
+            stream,
+            route,
+            StreamUpstream::Http2(upstream.sender),
+            Some(upstream_closed),
+        )
+        .await
+    }
+
+    /// Serve an HTTP/2 connection, forwarding streams to `upstream`
+    ///
+    /// The connection is gracefully shut down once `upstream_closed`
+    /// resolves.
+    pub(crate) async fn serve_http2(
+        &self,
+        stream: impl AsyncStream,
+        route: UpstreamRoute,
+        upstream: StreamUpstream,
+        upstream_closed: Option<oneshot::Receiver<()>>,
+    ) -> eyre::Result<()> {
+        let service = Http2Service {
+            handler: self.clone(),
+            route,
+            upstream,
+            http1_pool: Arc::default(),
+        };
+
+        let connection = http2::Builder::new(TokioExecutor::new())
+            .enable_connect_protocol()
+            .serve_connection(TokioIo::new(stream), service);
+        tokio::pin!(connection);
+        if let Some(mut upstream_closed) = upstream_closed {
+            tokio::select! {
+                result = &mut connection => {
+                    return result.wrap_err("error serving HTTP/2 connection");
+                }
+                _ = &mut upstream_closed => {
+                    debug!(peer_addr = %self.peer_addr, "upstream HTTP/2 connection closed");
+                    connection.as_mut().graceful_shutdown();
+                }
+            }
+        }
+        connection.await.wrap_err("error serving HTTP/2 connection")
+    }
+
+    /// Forward a stream upstream
+    ///
+    /// Errors reset the client's stream, with the same reason if upstream
+    /// reset it.
+    async fn handle_http2_request(
+        &self,
+        request: Request<Incoming>,
+        route: &UpstreamRoute,
+        upstream: StreamUpstream,
+        http1_pool: &Http1Pool,
+    ) -> Result<Response<ProxyBody>, Box<dyn Error + Send + Sync>> {
+        if diagnostics::is_diagnostic_request(&request, Some(route)) {
+            return Ok(self.diagnostic_response(request, Some(route)).await);
+        }
+        // WebSockets are always opened with an HTTP/1.1 upgrade upstream
+        let upstream = match UpgradeKind::of(&request) {
+            Some(_) => StreamUpstream::Http1,
+            None => upstream,
+        };
+        let forwarded = match upstream {
+            StreamUpstream::Http2(sender) => {
+                let send = |request| send_http2(sender, request);
+                self.forward_request(request, route, send).await
+            }
+            StreamUpstream::Http1 => {
+                let send = |request| http1_pool.send(self, route, request);
+                self.forward_request(request, route, send).await
+            }
+        };
+        forwarded.map_err(Into::into)
+    }
+
+    /// Answer every stream on an HTTP/2 connection with an error
+    pub(crate) async fn serve_http2_error(
+        &self,
+        stream: impl AsyncStream,
+        status: StatusCode,
+        message: String,
+    ) -> eyre::Result<()> {
+        let service = service_fn(move |_request| {
+            let response = text_response(status, message.clone());
+            async move { Ok::<_, Infallible>(response) }
+        });
+
+        http2::Builder::new(TokioExecutor::new())
+            .serve_connection(TokioIo::new(stream), service)
+            .await
+            .wrap_err("error serving HTTP/2 connection")
+    }
+
+    /// Connect to upstream for forwarding HTTP/2 streams
+    ///
+    /// Plaintext upstreams are assumed to support HTTP/2 with prior knowledge.
+    pub(crate) async fn connect_http2(&self, route: &UpstreamRoute) -> eyre::Result<Http2Upstream> {
+        if route.tls {
+            let stream = self
+                .connect_upstream_tls(
+                    &route.target,
+                    route.server_name.as_deref(),
+                    &[ALPN_HTTP2.to_vec()],
+                )
+                .await?;
+            if stream.get_ref().1.alpn_protocol() != Some(ALPN_HTTP2) {
+                eyre::bail!("upstream {} did not negotiate HTTP/2", route.target);
+            }
+            self.http2_handshake(stream).await
+        } else {
+            let stream = self
+                .connect_upstream(&route.target)
+                .await
+                .wrap_err_with(|| format!("failed to connect to {}", route.target))?;
+            self.http2_handshake(stream).await
+        }
+    }
+
+    /// Start an HTTP/2 client connection over `stream`
+    pub(crate) async fn http2_handshake(
+        &self,
+        stream: impl AsyncStream,
+    ) -> eyre::Result<Http2Upstream> {
+        let (sender, connection) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                .await
+                .wrap_err("upstream HTTP/2 handshake failed")?;
+        let (closed_sender, closed) = oneshot::channel();
+        let peer_addr = self.peer_addr;
+        tokio::spawn(async move {
+            if let Err(err) = connection.await {
+                debug!(?err, %peer_addr, "upstream HTTP/2 connection failed");
+            }
+            drop(closed_sender);
+        });
+        Ok(Http2Upstream { sender, closed })
+    }
+}
@@ -0,0 +1,190 @@
+//! Hosts learned to reject intercepted TLS
+//!
+//! Clients which pin certificates abort the handshake when served a minted
+//! certificate. Hosts where that happens are remembered and passed through on
+//! subsequent connections.
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use eyre::Context;
+use fjall::{Config, Keyspace, PartitionCreateOptions, PartitionHandle, PersistMode};
+use moka::Expiry;
+use moka::future::Cache;
+use time::OffsetDateTime;
+use tracing::{info, warn};
+
+const PARTITION_NAME: &str = "hosts";
+
+fn normalize(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// fjall-backed store of learned hosts
+///
+/// Entries are keyed by hostname, with the time the host was learned as an
+/// i64 unix timestamp.
+pub struct LearnedStore {
+    keyspace: Keyspace,
+    partition: PartitionHandle,
+}
+
+impl LearnedStore {
+    pub fn open(path: &Path) -> eyre::Result<Self> {
+        let keyspace = Config::new(path)
+            .open()
+            .wrap_err("failed to open learned passthrough store")?;
+        let partition = keyspace
+            .open_partition(PARTITION_NAME, PartitionCreateOptions::default())
+            .wrap_err("failed to open learned passthrough store partition")?;
+        Ok(LearnedStore {
+            keyspace,
+            partition,
+        })
+    }
+
+    pub fn insert(&self, host: &str, learned_at: OffsetDateTime) -> eyre::Result<()> {
+        self.partition
+            .insert(host, learned_at.unix_timestamp().to_be_bytes().to_vec())
+            .wrap_err("failed to write learned passthrough store")
+    }
+
+    /// Load all hosts learned within `time_to_live`, removing older entries
+    pub fn load_all(&self, time_to_live: Duration) -> eyre::Result<Vec<(String, OffsetDateTime)>> {
+        let now = OffsetDateTime::now_utc();
+        let mut loaded = Vec::new();
+        let mut discarded = 0usize;
+        for entry in self.partition.iter() {
+            let (key, value) = entry.wrap_err("failed to read learned passthrough store")?;
+            let host = std::str::from_utf8(&key).ok().map(normalize);
+            let learned_at = <[u8; 8]>::try_from(&value[..])
+                .ok()
+                .and_then(|value| {
+                    OffsetDateTime::from_unix_timestamp(i64::from_be_bytes(value)).ok()
+                })
+                .filter(|learned_at| *learned_at + time_to_live > now);
+
+            match (host, learned_at) {
+                (Some(host), Some(learned_at)) => loaded.push((host, learned_at)),
+                _ => {
+                    self.partition
+                        .remove(key)
+                        .wrap_err("failed to remove learned host")?;
+                    discarded += 1;
+                }
+            }
+        }
+
+        if discarded > 0 {
+            self.keyspace
+                .persist(PersistMode::SyncAll)
+                .wrap_err("failed to persist learned passthrough store")?;
+        }
+        info!(
+            loaded = loaded.len(),
+            discarded, "loaded learned passthrough hosts from store"
+        );
+        Ok(loaded)
+    }
+}
+
+pub struct LearnedPassthroughConfig {
+    /// Maximum number of hosts to remember
+    pub max_capacity: u64,
+    /// How long a host is passed through after it was learned
+    pub time_to_live: Duration,
+}
+
+impl Default for LearnedPassthroughConfig {
+    fn default() -> Self {
+        LearnedPassthroughConfig {
+            max_capacity: 10_000,
+            time_to_live: Duration::from_secs(60 * 60 * 24 * 30),
+        }
+    }
+}
+
+/// Expire entries at the instant stored as their value
+struct LearnedExpiry;
+
+impl Expiry<String, Instant> for LearnedExpiry {
+    fn expire_after_create(
+        &self,
+        _key: &String,
+        expires_at: &Instant,
+        created_at: Instant,
+    ) -> Option<Duration> {
+        Some(expires_at.saturating_duration_since(created_at))
+    }
+}
+
+/// Hosts which rejected a minted certificate
+///
+/// If a store is provided, learned hosts are also persisted to it.
+pub struct LearnedPassthrough {
+    hosts: Cache<String, Instant>,
+    store: Option<LearnedStore>,
+    time_to_live: Duration,
+}
+
+impl LearnedPassthrough {
+    pub fn new(config: LearnedPassthroughConfig, store: Option<LearnedStore>) -> Self {
+        let hosts = Cache::builder()
+            .max_capacity(config.max_capacity)
+            .expire_after(LearnedExpiry)
+            .build();
+        LearnedPassthrough {
+            hosts,
+            store,
+            time_to_live: config.time_to_live,
+        }
+    }
+
+    /// Populate learned hosts from the store
+    pub async fn load_from_store(&self) -> eyre::Result<()> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        let now = OffsetDateTime::now_utc();
+        for (host, learned_at) in store.load_all(self.time_to_live)? {
+            let remaining = (learned_at + self.time_to_live - now)
+                .try_into()
+                .unwrap_or(Duration::ZERO);
+            self.hosts.insert(host, Instant::now() + remaining).await;
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, host: &str) -> bool {
+        self.hosts.contains_key(&normalize(host))
+    }
+
+    /// Remember that `host` rejected a minted certificate
+    pub async fn learn(&self, host: &str) {
+        let host = normalize(host);
+        info!(%host, "client rejected minted certificate, passing through from now on");
+        if let Some(store) = &self.store
+            && let Err(err) = store.insert(&host, OffsetDateTime::now_utc())
+        {
+            warn!(?err, %host, "failed to persist learned host");
+        }
+        self.hosts
+            .insert(host, Instant::now() + self.time_to_live)
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LearnedPassthrough, LearnedPassthroughConfig};
+
+    #[tokio::test]
+    async fn learn_hosts() {
+        let learned = LearnedPassthrough::new(LearnedPassthroughConfig::default(), None);
+        assert!(!learned.contains("pinned.example"));
+        learned.learn("Pinned.Example.").await;
+        assert!(learned.contains("pinned.example"));
+        assert!(learned.contains("PINNED.example."));
+        assert!(!learned.contains("other.example"));
+    }
+}
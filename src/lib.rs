@@ -1,6 +1,27 @@
 pub mod avail_list;
 pub mod ca;
+pub mod capture;
+pub mod cert_cache;
+pub mod cert_store;
+pub mod client_hello;
 pub mod common;
+pub mod diagnostics;
+pub mod flow;
+pub mod forward;
+pub mod h2c;
+pub mod http1;
+pub mod http2;
+pub mod learned;
+pub mod mimic;
+pub mod policy;
 pub mod pool;
+pub mod proxy_protocol;
+pub mod relay;
 pub mod replay_buffer;
 pub mod server;
+pub mod socks;
+pub mod target;
+pub mod tls;
+pub mod transparent;
+pub mod upstream;
+pub mod websocket;
@@ -0,0 +1,290 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::Parser;
+use rs_mitm::ca::load_or_create_ca;
+use rs_mitm::cert_cache::{CertificateCache, CertificateCacheConfig};
+use rs_mitm::cert_store::CertificateStore;
+use rs_mitm::common;
+use rs_mitm::flow::{self, FlowRecorder};
+use rs_mitm::learned::{LearnedPassthrough, LearnedPassthroughConfig, LearnedStore};
+use rs_mitm::policy::{HostPattern, InterceptPolicy};
+use rs_mitm::proxy_protocol::ProxyProtocolVersion;
+use rs_mitm::server::{FallbackAction, Listener, ListenerConfig, ListenerMode, SharedState};
+use rs_mitm::socks::SocksCredentials;
+use rs_mitm::upstream::{UpstreamConnector, UpstreamTrust};
+use tokio::task::JoinSet;
+
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// Address to listen on for explicit proxy clients (may be specified
+    /// multiple times)
+    #[arg(short, long)]
+    listen: Vec<SocketAddr>,
+    /// Address to listen on for connections redirected with iptables REDIRECT
+    /// (may be specified multiple times)
+    #[arg(long)]
+    transparent: Vec<SocketAddr>,
+    /// Address to listen on for connections delivered with iptables TPROXY
+    /// (may be specified multiple times)
+    #[arg(long)]
+    tproxy: Vec<SocketAddr>,
+    /// Use the client address as the source of upstream connections from
+    /// TPROXY listeners
+    #[arg(long)]
+    tproxy_spoof_source: bool,
+    /// Directory for CA certificate/key and other state
+    #[arg(long, default_value = "data")]
+    data_dir: PathBuf,
+    /// Maximum number of bytes to read while sniffing the protocol
+    #[arg(long, default_value_t = 4096)]
+    max_preamble_length: usize,
+    /// Maximum number of bytes buffered for replay while sniffing
+    #[arg(long, default_value_t = 16384)]
+    max_replay_length: usize,
+    /// Maximum number of bytes of each HTTP body, or each direction of an
+    /// unrecognized protocol, to record
+    #[arg(long, default_value_t = 1024 * 1024)]
+    max_body_capture: usize,
+    /// Maximum number of bytes of WebSocket message payloads to record per
+    /// connection
+    #[arg(long, default_value_t = 16 * 1024 * 1024)]
+    max_websocket_capture: usize,
+    /// Maximum number of WebSocket messages to record per connection
+    #[arg(long, default_value_t = 10_000)]
+    max_websocket_messages: usize,
+    /// Maximum number of minted certificates to cache
+    #[arg(long, default_value_t = 10_000)]
+    cert_cache_capacity: u64,
+    /// How long minted certificates are reused for, in seconds
+    #[arg(long, default_value_t = 60 * 60 * 24 * 7)]
+    cert_cache_ttl: u64,
+    /// Do not persist minted certificates to the data directory
+    #[arg(long)]
+    no_cert_store: bool,
+    /// Never intercept TLS to hosts matching this pattern (may be specified
+    /// multiple times). Patterns are `example.com` (exact), `.example.com`
+    /// or `suffix:example.com` (domain and subdomains), `*.example.com`
+    /// (wildcard), or `regex:<regex>`
+    #[arg(long, value_parser = parse_host_pattern)]
+    passthrough: Vec<HostPattern>,
+    /// Only intercept TLS to hosts matching this pattern (may be specified
+    /// multiple times)
+    #[arg(long, value_parser = parse_host_pattern)]
+    intercept_only: Vec<HostPattern>,
+    /// Pass through TLS to hosts after a client rejects a minted certificate
+    /// for them
+    #[arg(long)]
+    learn_passthrough: bool,
+    /// How long learned hosts are passed through for, in seconds
+    #[arg(long, default_value_t = 60 * 60 * 24 * 30)]
+    learned_passthrough_ttl: u64,
+    /// Do not persist learned hosts to the data directory
+    #[arg(long)]
+    no_learned_store: bool,
+    /// Certificates trusted for upstream connections: `webpki` (bundled
+    /// Mozilla roots), `system`, `file:<path>` (PEM bundle), or `insecure`
+    /// (do not verify)
+    #[arg(long, default_value = "webpki", value_parser = parse_upstream_trust)]
+    upstream_trust: UpstreamTrust,
+    /// Copy the subject, names, validity and key usage of upstream
+    /// certificates into minted certificates
+    #[arg(long)]
+    mimic_upstream_certs: bool,
+    /// Send HTTP/2 with prior knowledge to cleartext upstreams matching this
+    /// pattern, including for requests from HTTP/1 clients (may be specified
+    /// multiple times)
+    #[arg(long, value_parser = parse_host_pattern)]
+    h2c_upstream: Vec<HostPattern>,
+    /// Add a `Via` header with this pseudonym to forwarded requests and
+    /// responses
+    #[arg(long, value_parser = parse_via)]
+    via: Option<String>,
+    /// What to do with connections in an unrecognized protocol: `drop`,
+    /// `relay` to the original destination, or relay and `capture` the bytes
+    /// exchanged. Only connections with a known destination can be relayed
+    #[arg(long, default_value = "drop", value_parser = parse_fallback)]
+    fallback: FallbackAction,
+    /// Override `--fallback` for one listener, as `address=action` (may be
+    /// specified multiple times)
+    #[arg(long, value_parser = parse_listener_fallback)]
+    listener_fallback: Vec<(SocketAddr, FallbackAction)>,
+    /// Treat connections as unrecognized if the client sends nothing for this
+    /// many seconds, so server-speaks-first protocols reach the fallback
+    #[arg(long)]
+    sniff_timeout: Option<u64>,
+    /// Drop SSLv2-compatible ClientHellos instead of passing them through to
+    /// their original destination
+    #[arg(long)]
+    reject_legacy_tls: bool,
+    /// Require SOCKS clients of explicit listeners to authenticate with
+    /// `username:password`
+    #[arg(long, value_parser = parse_socks_credentials)]
+    socks_auth: Option<SocksCredentials>,
+    /// Require connections to all listeners to start with a PROXY protocol
+    /// v1 or v2 header, as sent by load balancers
+    #[arg(long)]
+    accept_proxy_protocol: bool,
+    /// Send a PROXY protocol header (`v1` or `v2`) with the client address to
+    /// upstreams
+    #[arg(long, value_parser = parse_proxy_protocol_version)]
+    upstream_proxy_protocol: Option<ProxyProtocolVersion>,
+}
+
+fn parse_host_pattern(s: &str) -> eyre::Result<HostPattern> {
+    s.parse()
+}
+
+fn parse_via(s: &str) -> eyre::Result<String> {
+    if s.is_empty() || !s.bytes().all(|byte| byte.is_ascii_graphic()) {
+        eyre::bail!("Via pseudonym must be a single token");
+    }
+    Ok(s.to_owned())
+}
+
+fn parse_socks_credentials(s: &str) -> eyre::Result<SocksCredentials> {
+    s.parse()
+}
+
+fn parse_fallback(s: &str) -> eyre::Result<FallbackAction> {
+    s.parse()
+}
+
+fn parse_listener_fallback(s: &str) -> eyre::Result<(SocketAddr, FallbackAction)> {
+    let Some((address, action)) = s.rsplit_once('=') else {
+        eyre::bail!("listener fallback must be of the form address=action");
+    };
+    Ok((address.parse()?, action.parse()?))
+}
+
+fn parse_proxy_protocol_version(s: &str) -> eyre::Result<ProxyProtocolVersion> {
+    s.parse()
+}
+
+fn parse_upstream_trust(s: &str) -> eyre::Result<UpstreamTrust> {
+    s.parse()
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    common::initialize_logging();
+    let args = Args::parse();
+
+    let ca = load_or_create_ca(&args.data_dir).await?;
+    let store = if args.no_cert_store {
+        None
+    } else {
+        Some(CertificateStore::open(
+            &args.data_dir.join("certificates"),
+            &ca,
+        )?)
+    };
+    let certificates = CertificateCache::new(
+        CertificateCacheConfig {
+            max_capacity: args.cert_cache_capacity,
+            time_to_live: Duration::from_secs(args.cert_cache_ttl),
+        },
+        store,
+    );
+    let crypto_provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+    certificates.load_from_store(&crypto_provider).await?;
+
+    let learned = if args.learn_passthrough {
+        let store = if args.no_learned_store {
+            None
+        } else {
+            Some(LearnedStore::open(
+                &args.data_dir.join("learned-passthrough"),
+            )?)
+        };
+        let learned = LearnedPassthrough::new(
+            LearnedPassthroughConfig {
+                time_to_live: Duration::from_secs(args.learned_passthrough_ttl),
+                ..Default::default()
+            },
+            store,
+        );
+        learned.load_from_store().await?;
+        Some(learned)
+    } else {
+        None
+    };
+
+    let upstream = UpstreamConnector::new(Arc::clone(&crypto_provider), &args.upstream_trust)?;
+
+    let (flows, flow_receiver) = FlowRecorder::new(1024);
+    tokio::spawn(flow::log_flows(flow_receiver));
+
+    let shared = Arc::new(SharedState {
+        ca,
+        crypto_provider,
+        certificates,
+        policy: InterceptPolicy {
+            passthrough: args.passthrough,
+            intercept_only: args.intercept_only,
+        },
+        flows,
+        learned,
+        upstream,
+        mimic_certificates: args.mimic_upstream_certs,
+        h2c_upstreams: args.h2c_upstream,
+        via: args.via,
+    });
+
+    let mut listen = args.listen;
+    if listen.is_empty() && args.transparent.is_empty() && args.tproxy.is_empty() {
+        listen.push(SocketAddr::from(([127, 0, 0, 1], 8080)));
+    }
+    let modes = listen
+        .into_iter()
+        .map(|address| (address, ListenerMode::Explicit))
+        .chain(
+            args.transparent
+                .into_iter()
+                .map(|address| (address, ListenerMode::Transparent)),
+        )
+        .chain(
+            args.tproxy
+                .into_iter()
+                .map(|address| (address, ListenerMode::Tproxy)),
+        );
+    let modes: Vec<_> = modes.collect();
+    if let Some((address, _)) = args
+        .listener_fallback
+        .iter()
+        .find(|(address, _)| !modes.iter().any(|(listener, _)| listener == address))
+    {
+        eyre::bail!("--listener-fallback given for {address}, which is not a listener");
+    }
+
+    let mut listeners = JoinSet::new();
+    for (address, mode) in modes {
+        let mut config = ListenerConfig::new(address, mode);
+        config.spoof_source = mode == ListenerMode::Tproxy && args.tproxy_spoof_source;
+        config.max_preamble_length = args.max_preamble_length;
+        config.max_replay_length = args.max_replay_length;
+        config.max_body_capture = args.max_body_capture;
+        config.max_websocket_capture = args.max_websocket_capture;
+        config.max_websocket_messages = args.max_websocket_messages;
+        config.fallback = args
+            .listener_fallback
+            .iter()
+            .find(|(listener, _)| *listener == address)
+            .map_or(args.fallback, |(_, fallback)| *fallback);
+        config.sniff_timeout = args.sniff_timeout.map(Duration::from_secs);
+        config.reject_legacy_tls = args.reject_legacy_tls;
+        config.socks_credentials = args.socks_auth.clone();
+        config.accept_proxy_protocol = args.accept_proxy_protocol;
+        config.upstream_proxy_protocol = args.upstream_proxy_protocol;
+        let listener = Listener::new(Arc::clone(&shared), config).await?;
+        listeners.spawn(listener.run());
+    }
+
+    while let Some(result) = listeners.join_next().await {
+        result??;
+    }
+    Ok(())
+}
@@ -0,0 +1,333 @@
+//! Copying details of upstream certificates into minted certificates
+//!
+//! Minted certificates normally only contain a common name and the requested
+//! names. When mimicking, the subject, names, validity and key usage of the
+//! certificate presented by upstream are copied instead.
+
+use std::net::IpAddr;
+
+use eyre::Context;
+use rcgen::{
+    DistinguishedName, DnType, DnValue, ExtendedKeyUsagePurpose, KeyUsagePurpose, SanType,
+};
+use rustls_pki_types::CertificateDer;
+use time::{Duration, OffsetDateTime};
+use tracing::debug;
+use x509_parser::der_parser::asn1_rs::Tag;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::ca::{self, CertificateWithKey, SigningCA};
+use crate::cert_cache::MIN_MIMICKED_TTL;
+
+/// Longest validity period of a mimicked certificate
+///
+/// Clients commonly reject server certificates valid for longer than 398 days.
+pub const MAX_VALIDITY: Duration = Duration::days(397);
+
+/// Details copied from an upstream certificate
+#[derive(Debug, Clone)]
+pub struct MimicTemplate {
+    /// Subject, if it had any attributes representable by rcgen
+    pub subject: Option<DistinguishedName>,
+    /// DNS and IP subject alternative names
+    pub subject_alt_names: Vec<SanType>,
+    pub not_before: OffsetDateTime,
+    pub not_after: OffsetDateTime,
+    pub key_usages: Vec<KeyUsagePurpose>,
+    pub extended_key_usages: Vec<ExtendedKeyUsagePurpose>,
+}
+
+impl MimicTemplate {
+    /// Parse an upstream end-entity certificate
+    pub fn from_der(der: &CertificateDer<'_>) -> eyre::Result<Self> {
+        let (_, cert) =
+            X509Certificate::from_der(der).wrap_err("failed to parse upstream certificate")?;
+
+        let mut subject = DistinguishedName::new();
+        let mut has_subject = false;
+        for attribute in cert.subject().iter_attributes() {
+            let Some(oid) = attribute.attr_type().iter() else {
+                continue;
+            };
+            let oid: Vec<u64> = oid.collect();
+            let Ok(value) = attribute.as_str() else {
+                continue;
+            };
+            let value = match attribute.attr_value().header.tag() {
+                Tag::PrintableString => match value.try_into() {
+                    Ok(printable) => DnValue::PrintableString(printable),
+                    Err(_) => DnValue::Utf8String(value.to_owned()),
+                },
+                _ => DnValue::Utf8String(value.to_owned()),
+            };
+            subject.push(DnType::from_oid(&oid), value);
+            has_subject = true;
+        }
+
+        let mut subject_alt_names = Vec::new();
+        if let Some(san) = cert
+            .subject_alternative_name()
+            .wrap_err("invalid subject alternative names")?
+        {
+            for name in &san.value.general_names {
+                match name {
+                    GeneralName::DNSName(dns) => match (*dns).try_into() {
+                        Ok(dns) => subject_alt_names.push(SanType::DnsName(dns)),
+                        Err(err) => debug!(?err, dns, "skipping invalid DNS name"),
+                    },
+                    GeneralName::IPAddress(bytes) => match ip_from_bytes(bytes) {
+                        Some(address) => subject_alt_names.push(SanType::IpAddress(address)),
+                        None => debug!(?bytes, "skipping invalid IP address"),
+                    },
+                    // other names cannot be used as certificate cache keys
+                    _ => {}
+                }
+            }
+        }
+
+        let mut key_usages = Vec::new();
+        if let Some(usage) = cert.key_usage().wrap_err("invalid key usage")? {
+            let usage = usage.value;
+            for (set, purpose) in [
+                (usage.digital_signature(), KeyUsagePurpose::DigitalSignature),
+                (usage.non_repudiation(), KeyUsagePurpose::ContentCommitment),
+                (usage.key_encipherment(), KeyUsagePurpose::KeyEncipherment),
+                (usage.data_encipherment(), KeyUsagePurpose::DataEncipherment),
+                (usage.key_agreement(), KeyUsagePurpose::KeyAgreement),
+                (usage.encipher_only(), KeyUsagePurpose::EncipherOnly),
+                (usage.decipher_only(), KeyUsagePurpose::DecipherOnly),
+            ] {
+                if set {
+                    key_usages.push(purpose);
+                }
+            }
+        }
+
+        let mut extended_key_usages = Vec::new();
+        if let Some(usage) = cert
+            .extended_key_usage()
+            .wrap_err("invalid extended key usage")?
+        {
+            let usage = usage.value;
+            for (set, purpose) in [
+                (usage.any, ExtendedKeyUsagePurpose::Any),
+                (usage.server_auth, ExtendedKeyUsagePurpose::ServerAuth),
+                (usage.client_auth, ExtendedKeyUsagePurpose::ClientAuth),
+                (usage.code_signing, ExtendedKeyUsagePurpose::CodeSigning),
+                (
+                    usage.email_protection,
+                    ExtendedKeyUsagePurpose::EmailProtection,
+                ),
+                (usage.time_stamping, ExtendedKeyUsagePurpose::TimeStamping),
+                (usage.ocsp_signing, ExtendedKeyUsagePurpose::OcspSigning),
+            ] {
+                if set {
+                    extended_key_usages.push(purpose);
+                }
+            }
+        }
+
+        Ok(MimicTemplate {
+            subject: has_subject.then_some(subject),
+            subject_alt_names,
+            not_before: cert.validity().not_before.to_datetime(),
+            not_after: cert.validity().not_after.to_datetime(),
+            key_usages,
+            extended_key_usages,
+        })
+    }
+
+    /// Validity period limited to within `ca_not_before..ca_not_after` and to
+    /// at most `MAX_VALIDITY`
+    ///
+    /// The period is extended to last at least `MIN_MIMICKED_TTL` past `now`
+    /// if the CA allows, so an expired upstream certificate is not copied as
+    /// an already expired certificate.
+    pub fn clamped_validity(
+        &self,
+        now: OffsetDateTime,
+        ca_not_before: OffsetDateTime,
+        ca_not_after: OffsetDateTime,
+    ) -> (OffsetDateTime, OffsetDateTime) {
+        let not_before = self.not_before.max(ca_not_before);
+        let not_after = self
+            .not_after
+            .min(not_before + MAX_VALIDITY)
+            .max(now + MIN_MIMICKED_TTL)
+            .min(ca_not_after)
+            .max(not_before);
+        (not_before, not_after)
+    }
+}
+
+fn ip_from_bytes(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => Some(IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+        16 => Some(IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+        _ => None,
+    }
+}
+
+/// Key usages for a minted certificate, which always has an ECDSA key
+///
+/// The encipherment usages only apply to RSA keys, and the key must be usable
+/// to sign the handshake.
+fn ecdsa_key_usages(upstream: &[KeyUsagePurpose]) -> Vec<KeyUsagePurpose> {
+    let mut usages = vec![KeyUsagePurpose::DigitalSignature];
+    usages.extend(
+        upstream
+            .iter()
+            .filter(|usage| {
+                !matches!(
+                    usage,
+                    KeyUsagePurpose::DigitalSignature
+                        | KeyUsagePurpose::KeyEncipherment
+                        | KeyUsagePurpose::DataEncipherment
+                )
+            })
+            .cloned(),
+    );
+    usages
+}
+
+impl SigningCA {
+    /// Create a certificate for names which copies details from an upstream
+    /// certificate
+    ///
+    /// Key usages are only copied if upstream specified them, adjusted to suit
+    /// the minted key, and the validity period is clamped to fit within the
+    /// CA's.
+    pub fn create_cert_mimicking(
+        &self,
+        names: Vec<SanType>,
+        template: &MimicTemplate,
+    ) -> CertificateWithKey {
+        let mut params = ca::leaf_params(names);
+        if let Some(subject) = &template.subject {
+            params.distinguished_name = subject.clone();
+        }
+        if !template.key_usages.is_empty() {
+            params.key_usages = ecdsa_key_usages(&template.key_usages);
+        }
+        if !template.extended_key_usages.is_empty() {
+            params.extended_key_usages = template.extended_key_usages.clone();
+        }
+        (params.not_before, params.not_after) = template.clamped_validity(
+            OffsetDateTime::now_utc(),
+            self.ca_signing_params.not_before,
+            self.ca_signing_params.not_after,
+        );
+        self.sign_leaf(params)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rcgen::{
+        CertificateParams, DnType, ExtendedKeyUsagePurpose, KeyPair, KeyUsagePurpose, SanType,
+    };
+    use time::{Duration, OffsetDateTime};
+
+    use super::{MAX_VALIDITY, MimicTemplate};
+    use crate::ca::SigningCA;
+    use crate::cert_cache::MIN_MIMICKED_TTL;
+
+    #[test]
+    fn parse_template() {
+        let mut params = CertificateParams::new(vec!["example.com".into()]).unwrap();
+        params
+            .distinguished_name
+            .push(DnType::OrganizationName, "Example Inc");
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "example.com");
+        params
+            .subject_alt_names
+            .push(SanType::IpAddress([192, 0, 2, 1].into()));
+        params.key_usages = vec![
+            KeyUsagePurpose::DigitalSignature,
+            KeyUsagePurpose::KeyEncipherment,
+        ];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        let keypair = KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+        let cert = params.self_signed(&keypair).unwrap();
+
+        let template = MimicTemplate::from_der(cert.der()).unwrap();
+        let subject = template.subject.as_ref().unwrap();
+        assert_eq!(subject.iter().count(), 2);
+        assert!(subject.get(&DnType::OrganizationName).is_some());
+        assert_eq!(
+            template.subject_alt_names,
+            vec![
+                SanType::DnsName("example.com".try_into().unwrap()),
+                SanType::IpAddress([192, 0, 2, 1].into()),
+            ]
+        );
+        assert_eq!(
+            template.key_usages,
+            vec![
+                KeyUsagePurpose::DigitalSignature,
+                KeyUsagePurpose::KeyEncipherment,
+            ]
+        );
+        assert_eq!(
+            template.extended_key_usages,
+            vec![ExtendedKeyUsagePurpose::ServerAuth]
+        );
+    }
+
+    #[test]
+    fn mimic_rsa_key_usage() {
+        let now = OffsetDateTime::now_utc();
+        // as on an RSA certificate for key exchange
+        let template = MimicTemplate {
+            subject: None,
+            subject_alt_names: Vec::new(),
+            not_before: now - Duration::days(1),
+            not_after: now + Duration::days(30),
+            key_usages: vec![KeyUsagePurpose::KeyEncipherment],
+            extended_key_usages: Vec::new(),
+        };
+        let ca = SigningCA::make_ca();
+        let names = vec![SanType::DnsName("example.com".try_into().unwrap())];
+        let minted = ca.create_cert_mimicking(names, &template);
+
+        let parsed = MimicTemplate::from_der(&minted.certificate_chain[0]).unwrap();
+        assert_eq!(parsed.key_usages, vec![KeyUsagePurpose::DigitalSignature]);
+    }
+
+    #[test]
+    fn clamp_validity() {
+        let now = OffsetDateTime::now_utc().replace_nanosecond(0).unwrap();
+        let template = MimicTemplate {
+            subject: None,
+            subject_alt_names: Vec::new(),
+            not_before: now - Duration::days(100),
+            not_after: now + Duration::days(1000),
+            key_usages: Vec::new(),
+            extended_key_usages: Vec::new(),
+        };
+
+        let ca_not_before = now - Duration::days(10);
+        let (not_before, not_after) =
+            template.clamped_validity(now, ca_not_before, now + Duration::days(3650));
+        assert_eq!(not_before, ca_not_before);
+        assert_eq!(not_after, ca_not_before + MAX_VALIDITY);
+
+        let ca_not_after = now + Duration::days(30);
+        let (_, not_after) = template.clamped_validity(now, ca_not_before, ca_not_after);
+        assert_eq!(not_after, ca_not_after);
+
+        // upstream expired last week
+        let expired = MimicTemplate {
+            not_before: now - Duration::days(90),
+            not_after: now - Duration::days(7),
+            ..template
+        };
+        let (not_before, not_after) =
+            expired.clamped_validity(now, ca_not_before, now + Duration::days(3650));
+        assert_eq!(not_before, ca_not_before);
+        assert_eq!(not_after, now + MIN_MIMICKED_TTL);
+    }
+}
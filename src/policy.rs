@@ -0,0 +1,182 @@
+//! Interception policy
+
+use std::str::FromStr;
+
+use eyre::Context;
+use regex::Regex;
+
+/// Pattern matching a hostname
+///
+/// Parsed from strings of the following forms:
+/// - `regex:<regex>`: regular expression matched against the whole hostname
+/// - `suffix:example.com` or `.example.com`: the domain and all subdomains
+/// - `*.example.com`: glob where `*` matches within a single label
+/// - `example.com`: exact match
+#[derive(Debug, Clone)]
+pub enum HostPattern {
+    Exact(String),
+    Suffix(String),
+    Wildcard(Vec<String>),
+    Regex(Regex),
+}
+
+impl HostPattern {
+    pub fn matches(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.');
+        match self {
+            HostPattern::Exact(exact) => host.eq_ignore_ascii_case(exact),
+            HostPattern::Suffix(suffix) => {
+                let host = host.to_ascii_lowercase();
+                host == *suffix
+                    || host
+                        .strip_suffix(suffix.as_str())
+                        .is_some_and(|rest| rest.ends_with('.'))
+            }
+            HostPattern::Wildcard(labels) => {
+                let host = host.to_ascii_lowercase();
+                let host_labels: Vec<&str> = host.split('.').collect();
+                host_labels.len() == labels.len()
+                    && labels
+                        .iter()
+                        .zip(host_labels)
+                        .all(|(pattern, label)| glob_matches(pattern, label))
+            }
+            HostPattern::Regex(regex) => regex.is_match(host),
+        }
+    }
+}
+
+/// Match a single label against a pattern where `*` matches any run of
+/// characters
+fn glob_matches(pattern: &str, label: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = label.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // no wildcard at all
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+impl FromStr for HostPattern {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(regex) = s.strip_prefix("regex:") {
+            let regex = Regex::new(&format!("(?i)^(?:{regex})$"))
+                .wrap_err_with(|| format!("invalid host regex {regex:?}"))?;
+            return Ok(HostPattern::Regex(regex));
+        }
+
+        let s = s.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(suffix) = s.strip_prefix("suffix:").or_else(|| s.strip_prefix('.')) {
+            Ok(HostPattern::Suffix(suffix.to_owned()))
+        } else if s.contains('*') {
+            Ok(HostPattern::Wildcard(
+                s.split('.').map(str::to_owned).collect(),
+            ))
+        } else {
+            Ok(HostPattern::Exact(s))
+        }
+    }
+}
+
+/// What to do with a TLS connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsAction {
+    /// Terminate TLS with a minted certificate
+    Intercept,
+    /// Relay the connection to upstream without decrypting it
+    Passthrough,
+}
+
+/// Decides which TLS connections are intercepted
+#[derive(Debug, Clone, Default)]
+pub struct InterceptPolicy {
+    /// Hosts which are never intercepted
+    pub passthrough: Vec<HostPattern>,
+    /// If not empty, only these hosts are intercepted
+    pub intercept_only: Vec<HostPattern>,
+}
+
+impl InterceptPolicy {
+    /// Decide what to do with a connection to `host`
+    ///
+    /// Connections without a hostname are always intercepted.
+    pub fn decide(&self, host: Option<&str>) -> TlsAction {
+        let Some(host) = host else {
+            return TlsAction::Intercept;
+        };
+        if self.passthrough.iter().any(|pattern| pattern.matches(host)) {
+            return TlsAction::Passthrough;
+        }
+        if !self.intercept_only.is_empty()
+            && !self
+                .intercept_only
+                .iter()
+                .any(|pattern| pattern.matches(host))
+        {
+            return TlsAction::Passthrough;
+        }
+        TlsAction::Intercept
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HostPattern, InterceptPolicy, TlsAction};
+
+    fn pattern(s: &str) -> HostPattern {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn host_patterns() {
+        assert!(pattern("example.com").matches("Example.COM."));
+        assert!(!pattern("example.com").matches("www.example.com"));
+
+        assert!(pattern(".example.com").matches("example.com"));
+        assert!(pattern("suffix:example.com").matches("a.b.example.com"));
+        assert!(!pattern(".example.com").matches("badexample.com"));
+
+        assert!(pattern("*.example.com").matches("www.example.com"));
+        assert!(!pattern("*.example.com").matches("a.b.example.com"));
+        assert!(!pattern("*.example.com").matches("example.com"));
+        assert!(pattern("api-*.example.com").matches("api-eu.example.com"));
+        assert!(!pattern("api-*.example.com").matches("web-eu.example.com"));
+
+        assert!(pattern(r"regex:.*\.apple\.com").matches("gs.apple.com"));
+        assert!(!pattern(r"regex:apple\.com").matches("apple.com.evil"));
+    }
+
+    #[test]
+    fn policy_decisions() {
+        let policy = InterceptPolicy {
+            passthrough: vec![pattern(".pinned.example")],
+            intercept_only: vec![],
+        };
+        assert_eq!(
+            policy.decide(Some("api.pinned.example")),
+            TlsAction::Passthrough
+        );
+        assert_eq!(policy.decide(Some("example.com")), TlsAction::Intercept);
+        assert_eq!(policy.decide(None), TlsAction::Intercept);
+
+        let policy = InterceptPolicy {
+            passthrough: vec![],
+            intercept_only: vec![pattern("*.test")],
+        };
+        assert_eq!(policy.decide(Some("app.test")), TlsAction::Intercept);
+        assert_eq!(policy.decide(Some("example.com")), TlsAction::Passthrough);
+    }
+}
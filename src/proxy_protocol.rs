@@ -0,0 +1,295 @@
+//! HAProxy PROXY protocol headers
+//!
+//! See <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>. Headers
+//! are read without buffering past their end, so the stream can be sniffed
+//! afterwards.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+use eyre::{Context, bail};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// Longest v1 header including the trailing CRLF
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+const V2_VERSION: u8 = 0x20;
+const V2_COMMAND_LOCAL: u8 = 0x00;
+const V2_COMMAND_PROXY: u8 = 0x01;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+/// Longest TLV section accepted after the addresses of a v2 header
+const V2_MAX_TLV_LENGTH: usize = 1024;
+
+/// How long a peer has to send its whole header
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Version of the PROXY protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyProtocolVersion {
+    /// Human-readable text header
+    V1,
+    /// Binary header
+    V2,
+}
+
+impl FromStr for ProxyProtocolVersion {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1" | "v1" => Ok(ProxyProtocolVersion::V1),
+            "2" | "v2" => Ok(ProxyProtocolVersion::V2),
+            _ => bail!("unknown PROXY protocol version {s:?}, expected v1 or v2"),
+        }
+    }
+}
+
+/// Addresses of a proxied TCP connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxyHeader {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+impl ProxyHeader {
+    /// Read a v1 or v2 header from the start of `stream`
+    ///
+    /// Returns `None` for headers which do not carry addresses, such as those
+    /// sent by health checks.
+    pub async fn read(stream: &mut (impl AsyncRead + Unpin)) -> eyre::Result<Option<Self>> {
+        // shortest possible header is "PROXY UNKNOWN\r\n"
+        let mut start = [0u8; 12];
+        stream
+            .read_exact(&mut start)
+            .await
+            .wrap_err("failed to read PROXY header")?;
+        if &start == V2_SIGNATURE {
+            Self::read_v2(stream).await
+        } else if start.starts_with(V1_PREFIX) {
+            Self::read_v1(stream, &start).await
+        } else {
+            bail!("connection did not start with a PROXY header");
+        }
+    }
+
+    async fn read_v1(
+        stream: &mut (impl AsyncRead + Unpin),
+        start: &[u8],
+    ) -> eyre::Result<Option<Self>> {
+        let mut line = start.to_vec();
+        // byte at a time so nothing after the header is consumed
+        while !line.ends_with(b"\r\n") {
+            if line.len() == V1_MAX_LENGTH {
+                bail!("PROXY v1 header too long");
+            }
+            line.push(stream.read_u8().await?);
+        }
+        Self::parse_v1(&line[..line.len() - 2])
+    }
+
+    fn parse_v1(line: &[u8]) -> eyre::Result<Option<Self>> {
+        let line = std::str::from_utf8(line).wrap_err("PROXY v1 header is not ASCII")?;
+        let mut fields = line.split(' ').skip(1);
+        let protocol = fields.next().unwrap_or_default();
+        if protocol == "UNKNOWN" {
+            return Ok(None);
+        }
+        let (Some(source), Some(destination), Some(source_port), Some(destination_port), None) = (
+            fields.next(),
+            fields.next(),
+            fields.next(),
+            fields.next(),
+            fields.next(),
+        ) else {
+            bail!("malformed PROXY v1 header");
+        };
+        let parse_address = |address: &str| -> eyre::Result<IpAddr> {
+            let address = match protocol {
+                "TCP4" => IpAddr::V4(address.parse()?),
+                "TCP6" => IpAddr::V6(address.parse()?),
+                _ => bail!("unknown PROXY v1 protocol {protocol:?}"),
+            };
+            Ok(address)
+        };
+        Ok(Some(ProxyHeader {
+            source: SocketAddr::new(parse_address(source)?, source_port.parse()?),
+            destination: SocketAddr::new(parse_address(destination)?, destination_port.parse()?),
+        }))
+    }
+
+    async fn read_v2(stream: &mut (impl AsyncRead + Unpin)) -> eyre::Result<Option<Self>> {
+        let version_command = stream.read_u8().await?;
+        if version_command & 0xf0 != V2_VERSION {
+            bail!("unknown PROXY v2 version {:#x}", version_command >> 4);
+        }
+        let command = version_command & 0x0f;
+        if command != V2_COMMAND_LOCAL && command != V2_COMMAND_PROXY {
+            bail!("unknown PROXY v2 command {command:#x}");
+        }
+
+        let family = stream.read_u8().await?;
+        let length = stream.read_u16().await? as usize;
+        // addresses of IPv4, IPv6 and unix sockets respectively
+        let address_length = match family >> 4 {
+            1 => 12,
+            2 => 36,
+            3 => 216,
+            _ => 0,
+        };
+        if length > address_length + V2_MAX_TLV_LENGTH {
+            bail!("PROXY v2 header too long");
+        }
+        let mut payload = vec![0u8; length];
+        stream.read_exact(&mut payload).await?;
+        if command == V2_COMMAND_LOCAL {
+            return Ok(None);
+        }
+
+        // anything after the addresses is TLVs, which are ignored
+        let header = match family {
+            V2_TCP4 if payload.len() >= 12 => {
+                let address = |offset: usize| {
+                    let octets: [u8; 4] = payload[offset..offset + 4].try_into().unwrap();
+                    Ipv4Addr::from(octets).into()
+                };
+                let port =
+                    |offset: usize| u16::from_be_bytes([payload[offset], payload[offset + 1]]);
+                ProxyHeader {
+                    source: SocketAddr::new(address(0), port(8)),
+                    destination: SocketAddr::new(address(4), port(10)),
+                }
+            }
+            V2_TCP6 if payload.len() >= 36 => {
+                let address = |offset: usize| {
+                    let octets: [u8; 16] = payload[offset..offset + 16].try_into().unwrap();
+                    Ipv6Addr::from(octets).into()
+                };
+                let port =
+                    |offset: usize| u16::from_be_bytes([payload[offset], payload[offset + 1]]);
+                ProxyHeader {
+                    source: SocketAddr::new(address(0), port(32)),
+                    destination: SocketAddr::new(address(16), port(34)),
+                }
+            }
+            V2_TCP4 | V2_TCP6 => bail!("PROXY v2 header too short for its address family"),
+            // UDP, unix sockets and unspecified carry no usable TCP addresses
+            _ => return Ok(None),
+        };
+        Ok(Some(header))
+    }
+
+    /// Encode as a header to send before the proxied stream
+    pub fn encode(&self, version: ProxyProtocolVersion) -> Vec<u8> {
+        // both addresses must be in the same family
+        let (source, destination) = match (self.source, self.destination) {
+            (SocketAddr::V4(_), SocketAddr::V4(_)) | (SocketAddr::V6(_), SocketAddr::V6(_)) => {
+                (self.source, self.destination)
+            }
+            (source, destination) => (to_ipv6(source), to_ipv6(destination)),
+        };
+
+        match version {
+            ProxyProtocolVersion::V1 => {
+                let protocol = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+                format!(
+                    "PROXY {protocol} {} {} {} {}\r\n",
+                    source.ip(),
+                    destination.ip(),
+                    source.port(),
+                    destination.port()
+                )
+                .into_bytes()
+            }
+            ProxyProtocolVersion::V2 => {
+                let mut out = V2_SIGNATURE.to_vec();
+                out.push(V2_VERSION | V2_COMMAND_PROXY);
+                match (source.ip(), destination.ip()) {
+                    (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                        out.push(V2_TCP4);
+                        out.extend_from_slice(&12u16.to_be_bytes());
+                        out.extend_from_slice(&source.octets());
+                        out.extend_from_slice(&destination.octets());
+                    }
+                    (IpAddr::V6(source), IpAddr::V6(destination)) => {
+                        out.push(V2_TCP6);
+                        out.extend_from_slice(&36u16.to_be_bytes());
+                        out.extend_from_slice(&source.octets());
+                        out.extend_from_slice(&destination.octets());
+                    }
+                    _ => unreachable!("address families were unified"),
+                }
+                out.extend_from_slice(&source.port().to_be_bytes());
+                out.extend_from_slice(&destination.port().to_be_bytes());
+                out
+            }
+        }
+    }
+}
+
+fn to_ipv6(address: SocketAddr) -> SocketAddr {
+    match address.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(ip.to_ipv6_mapped().into(), address.port()),
+        IpAddr::V6(_) => address,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::AsyncWriteExt;
+
+    use super::{ProxyHeader, ProxyProtocolVersion};
+
+    #[tokio::test]
+    async fn round_trip() {
+        let header = ProxyHeader {
+            source: "192.0.2.1:51234".parse().unwrap(),
+            destination: "198.51.100.7:443".parse().unwrap(),
+        };
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            let mut encoded = header.encode(version);
+            encoded.extend_from_slice(b"GET / HTTP/1.1\r\n");
+            let mut stream = &encoded[..];
+            assert_eq!(ProxyHeader::read(&mut stream).await.unwrap(), Some(header));
+            // nothing past the header is consumed
+            assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+        }
+
+        let mixed = ProxyHeader {
+            source: "192.0.2.1:51234".parse().unwrap(),
+            destination: "[2001:db8::1]:443".parse().unwrap(),
+        };
+        assert_eq!(
+            mixed.encode(ProxyProtocolVersion::V1),
+            b"PROXY TCP6 ::ffff:192.0.2.1 2001:db8::1 51234 443\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn headers_without_addresses() {
+        let mut stream = &b"PROXY UNKNOWN\r\n"[..];
+        assert_eq!(ProxyHeader::read(&mut stream).await.unwrap(), None);
+
+        let mut local = b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00".as_slice();
+        assert_eq!(ProxyHeader::read(&mut local).await.unwrap(), None);
+
+        let mut garbage = &b"GET / HTTP/1.1\r\n"[..];
+        assert!(ProxyHeader::read(&mut garbage).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reject_before_payload() {
+        // the peer stays connected, so reading the payload would wait forever
+        for header in [
+            b"\r\n\r\n\0\r\nQUIT\n\x30\x11\x00\x0c",
+            b"\r\n\r\n\0\r\nQUIT\n\x21\x11\xff\xff",
+        ] {
+            let (mut client, mut server) = tokio::io::duplex(64);
+            client.write_all(header).await.unwrap();
+            assert!(ProxyHeader::read(&mut server).await.is_err());
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! Opaque relaying between two streams
+
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::common::AsyncStream;
+
+/// Result of relaying between client and upstream
+#[derive(Debug, Default)]
+pub struct RelayStats {
+    /// Bytes sent from client to upstream
+    pub bytes_sent: u64,
+    /// Bytes sent from upstream to client
+    pub bytes_received: u64,
+    /// First error encountered in either direction
+    pub error: Option<io::Error>,
+}
+
+/// Copy from reader to writer until EOF, then shut down the writer
+async fn copy_one_way(
+    mut reader: impl AsyncRead + Unpin,
+    mut writer: impl AsyncWrite + Unpin,
+    count: &mut u64,
+    inspect: &mut impl FnMut(&[u8]),
+) -> io::Result<()> {
+    let mut buf = vec![0u8; 16384];
+    loop {
+        let read = reader.read(&mut buf).await?;
+        if read == 0 {
+            return writer.shutdown().await;
+        }
+        writer.write_all(&buf[..read]).await?;
+        *count += read as u64;
+        inspect(&buf[..read]);
+    }
+}
+
+/// Relay between client and upstream until both directions are finished
+///
+/// Unlike `tokio::io::copy_bidirectional`, byte counts are kept even if the
+/// relay ends with an error.
+pub async fn relay(client: impl AsyncStream, upstream: impl AsyncStream) -> RelayStats {
+    relay_inspected(client, upstream, |_| {}, |_| {}).await
+}
+
+/// Relay between client and upstream, passing each chunk to `inspect_sent`
+/// or `inspect_received` after it has been written
+pub async fn relay_inspected(
+    client: impl AsyncStream,
+    upstream: impl AsyncStream,
+    mut inspect_sent: impl FnMut(&[u8]),
+    mut inspect_received: impl FnMut(&[u8]),
+) -> RelayStats {
+    let (client_read, client_write) = tokio::io::split(client);
+    let (upstream_read, upstream_write) = tokio::io::split(upstream);
+
+    let mut bytes_sent = 0;
+    let mut bytes_received = 0;
+    let result = {
+        let sent = copy_one_way(
+            client_read,
+            upstream_write,
+            &mut bytes_sent,
+            &mut inspect_sent,
+        );
+        let received = copy_one_way(
+            upstream_read,
+            client_write,
+            &mut bytes_received,
+            &mut inspect_received,
+        );
+        tokio::pin!(sent, received);
+
+        // an error in either direction ends the relay, otherwise wait for both
+        tokio::select! {
+            result = &mut sent => match result {
+                Ok(()) => received.await,
+                err => err,
+            },
+            result = &mut received => match result {
+                Ok(()) => sent.await,
+                err => err,
+            },
+        }
+    };
+    RelayStats {
+        bytes_sent,
+        bytes_received,
+        error: result.err(),
+    }
+}
@@ -1,45 +1,211 @@
-//! Buffer
+//! Buffer that records bytes read from a stream so they can be replayed
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
 
 use pin_project_lite::pin_project;
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Reads are passed through and recorded
+    Recording,
+    /// Reads return recorded bytes starting at `position`
+    Replaying { position: usize },
+    /// Recorded bytes have been exhausted, reads go directly to inner stream
+    Passthrough,
+}
 
 pin_project! {
+    /// Records bytes read from the inner stream so they can be replayed later
+    ///
+    /// Bytes are recorded until [`ReplayBuffer::rewind`] is called, after which
+    /// reads return the recorded bytes before continuing from the inner stream.
+    /// At most `max_length` bytes may be recorded; reads past that return an
+    /// error.
     pub struct ReplayBuffer<T: AsyncRead> {
         #[pin]
         inner: T,
+        buffer: Vec<u8>,
+        mode: Mode,
+        max_length: usize,
+    }
+}
+
+impl<T: AsyncRead> ReplayBuffer<T> {
+    pub fn new(inner: T, max_length: usize) -> Self {
+        ReplayBuffer {
+            inner,
+            buffer: Vec::new(),
+            mode: Mode::Recording,
+            max_length,
+        }
+    }
+
+    /// Replay `prefix` before reading from `inner`, without recording
+    pub fn with_prefix(inner: T, prefix: Vec<u8>) -> Self {
+        let mode = if prefix.is_empty() {
+            Mode::Passthrough
+        } else {
+            Mode::Replaying { position: 0 }
+        };
+        ReplayBuffer {
+            inner,
+            max_length: prefix.len(),
+            buffer: prefix,
+            mode,
+        }
+    }
+
+    /// Allow at least `max_length` bytes to be recorded
+    pub fn raise_max_length(&mut self, max_length: usize) {
+        self.max_length = self.max_length.max(max_length);
+    }
+
+    /// Bytes recorded so far
+    pub fn recorded(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// Whether bytes are still being recorded
+    pub fn is_recording(&self) -> bool {
+        self.mode == Mode::Recording
+    }
+
+    /// Stop recording and replay recorded bytes on subsequent reads
+    pub fn rewind(&mut self) {
+        assert!(self.is_recording(), "rewind called twice");
+        self.mode = if self.buffer.is_empty() {
+            Mode::Passthrough
+        } else {
+            Mode::Replaying { position: 0 }
+        };
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
     }
 }
 
+fn limit_exceeded() -> io::Error {
+    io::Error::other("replay buffer limit exceeded")
+}
+
 impl<T: AsyncRead> AsyncRead for ReplayBuffer<T> {
     fn poll_read(
-        self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-        buf: &mut tokio::io::ReadBuf<'_>,
-    ) -> std::task::Poll<std::io::Result<()>> {
-        todo!()
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        match *this.mode {
+            Mode::Recording => {
+                let remaining = *this.max_length - this.buffer.len();
+                if remaining == 0 {
+                    return Poll::Ready(Err(limit_exceeded()));
+                }
+
+                // only read as much as we are able to record
+                let mut limited = buf.take(remaining);
+                ready!(this.inner.poll_read(cx, &mut limited))?;
+                let count = limited.filled().len();
+                this.buffer.extend_from_slice(limited.filled());
+
+                // safety: `count` bytes were initialized by the inner read
+                unsafe { buf.assume_init(count) };
+                buf.advance(count);
+                Poll::Ready(Ok(()))
+            }
+            Mode::Replaying { position } => {
+                let pending = &this.buffer[position..];
+                let count = pending.len().min(buf.remaining());
+                buf.put_slice(&pending[..count]);
+
+                if position + count == this.buffer.len() {
+                    *this.mode = Mode::Passthrough;
+                    *this.buffer = Vec::new();
+                } else {
+                    *this.mode = Mode::Replaying {
+                        position: position + count,
+                    };
+                }
+                Poll::Ready(Ok(()))
+            }
+            Mode::Passthrough => this.inner.poll_read(cx, buf),
+        }
     }
 }
 
 impl<T: AsyncRead + AsyncWrite> AsyncWrite for ReplayBuffer<T> {
     fn poll_write(
-        self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
         buf: &[u8],
-    ) -> std::task::Poll<Result<usize, std::io::Error>> {
+    ) -> Poll<Result<usize, io::Error>> {
         self.project().inner.poll_write(cx, buf)
     }
 
-    fn poll_flush(
-        self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Result<(), std::io::Error>> {
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
         self.project().inner.poll_flush(cx)
     }
 
-    fn poll_shutdown(
-        self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Result<(), std::io::Error>> {
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
         self.project().inner.poll_shutdown(cx)
     }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<Result<usize, io::Error>> {
+        self.project().inner.poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::AsyncReadExt;
+
+    use super::ReplayBuffer;
+
+    #[tokio::test]
+    async fn record_and_replay() {
+        let mut stream = ReplayBuffer::new(&b"hello world"[..], 64);
+        let mut first = [0u8; 5];
+        stream.read_exact(&mut first).await.unwrap();
+        assert_eq!(&first, b"hello");
+        assert_eq!(stream.recorded(), b"hello");
+
+        stream.rewind();
+        let mut out = Vec::new();
+        stream.read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn replay_prefix() {
+        let mut stream = ReplayBuffer::with_prefix(&b" world"[..], b"hello".to_vec());
+        assert!(!stream.is_recording());
+        let mut out = Vec::new();
+        stream.read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn limit_exceeded() {
+        let mut stream = ReplayBuffer::new(&b"hello world"[..], 4);
+        let mut out = Vec::new();
+        let err = stream.read_to_end(&mut out).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Other);
+        assert_eq!(stream.recorded(), b"hell");
+    }
 }
@@ -2,45 +2,871 @@
 // connection handler struct has arc for shared state
 // need a sniffer to determine what protocol is being used
 // sniffer needs a way to replay the sniffed chunk(s)
-// for TLS, match b"\x16\x03\x01" (type = handshake, version = TLS 1.0),
+// for TLS, match b"\x16\x03" (type = handshake), a record version up to TLS 1.3,
 //   skip two bytes (length), then b"\x01" (type = client hello)
+// for SSLv2-compatible hellos, match a two byte header with the high bit set, then
+//   b"\x01" (type = client hello) and a version
 // for HTTP/2, match b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n" (last part probably optional)
-// for HTTP/1, match methods: GET, HEAD, POST, PUT, DELETE, CONNECT, OPTIONS, TRACE, PATCH
-//   also try to match r"^[A-Za-z0-9]+\s+[^\r\n]+\s+HTTP/\d" in first chunk maybe
-// otherwise, assume it's HTTP/1 anyways?
+// for SOCKS, match version byte 0x04 or 0x05, then a plausible second byte
+// for HTTP/1, match a method token, a request target, then b"HTTP/1." and a digit
+//   the HTTP/2 preface is the same up to the version
 
-pub struct Listener {}
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use eyre::Context;
+use hyper::StatusCode;
+use rustls::crypto::CryptoProvider;
+use time::OffsetDateTime;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::client::TlsStream;
+use tracing::{debug, error, info, warn};
+
+use crate::ca::SigningCA;
+use crate::capture::StreamCapture;
+use crate::cert_cache::{CertificateCache, SanSet};
+use crate::common::AsyncStream;
+use crate::flow::{
+    Direction, FlowRecord, FlowRecorder, PassthroughFlow, ProxiedConnection, RawStreamFlow,
+};
+use crate::learned::LearnedPassthrough;
+use crate::mimic::MimicTemplate;
+use crate::policy::{HostPattern, InterceptPolicy, TlsAction};
+use crate::proxy_protocol::{self, ProxyHeader, ProxyProtocolVersion};
+use crate::replay_buffer::ReplayBuffer;
+use crate::socks::SocksCredentials;
+use crate::target::{Host, Target};
+use crate::upstream::{UpstreamConnector, UpstreamRoute};
+use crate::{client_hello, diagnostics, h2c, relay, tls, transparent};
+
+/// How clients reach the listener
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenerMode {
+    /// Clients are configured to use the proxy, either with CONNECT or with
+    /// absolute-form requests
+    Explicit,
+    /// Connections are redirected to the listener with iptables REDIRECT and
+    /// the original destination is recovered with `SO_ORIGINAL_DST`
+    Transparent,
+    /// Connections are delivered to an `IP_TRANSPARENT` listener with iptables
+    /// TPROXY and the original destination is the local address
+    Tproxy,
+}
+
+/// What to do with connections in an unrecognized protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FallbackAction {
+    /// Close the connection
+    Drop,
+    /// Relay to the original destination without recording anything but a
+    /// passthrough flow
+    Relay,
+    /// Relay to the original destination, capturing both directions into a
+    /// raw stream flow
+    Capture,
+}
+
+impl FromStr for FallbackAction {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(FallbackAction::Drop),
+            "relay" => Ok(FallbackAction::Relay),
+            "capture" => Ok(FallbackAction::Capture),
+            _ => eyre::bail!("unknown fallback {s:?}, expected drop, relay or capture"),
+        }
+    }
+}
+
+/// Configuration for a single listener
+pub struct ListenerConfig {
+    /// Address to listen on
+    pub bind_address: SocketAddr,
+    /// How clients reach the listener
+    pub mode: ListenerMode,
+    /// Use the client address as the source of upstream connections (TPROXY
+    /// mode only)
+    pub spoof_source: bool,
+    /// Maximum number of bytes to read while sniffing before giving up. HTTP/1
+    /// is only detected once the request line up to `HTTP/1.` fits within it
+    pub max_preamble_length: usize,
+    /// Maximum number of bytes which may be buffered for replay
+    pub max_replay_length: usize,
+    /// Maximum number of bytes of each HTTP body, or each direction of a raw
+    /// stream, to record in flows
+    pub max_body_capture: usize,
+    /// Maximum number of bytes of WebSocket message payloads to record per
+    /// connection
+    pub max_websocket_capture: usize,
+    /// Maximum number of WebSocket messages to record per connection
+    pub max_websocket_messages: usize,
+    /// What to do with connections in an unrecognized protocol. Only
+    /// connections with a known destination (transparent, CONNECT or SOCKS)
+    /// can be relayed
+    pub fallback: FallbackAction,
+    /// Treat connections as unrecognized if the client sends nothing for this
+    /// long, for protocols where the server speaks first
+    pub sniff_timeout: Option<Duration>,
+    /// Drop SSLv2-compatible ClientHellos instead of passing them through
+    pub reject_legacy_tls: bool,
+    /// Credentials SOCKS clients must present (explicit mode only)
+    pub socks_credentials: Option<SocksCredentials>,
+    /// Require connections to start with a PROXY protocol header
+    pub accept_proxy_protocol: bool,
+    /// Send a PROXY protocol header with the client address to upstreams
+    pub upstream_proxy_protocol: Option<ProxyProtocolVersion>,
+}
+
+impl ListenerConfig {
+    pub fn new(bind_address: SocketAddr, mode: ListenerMode) -> Self {
+        ListenerConfig {
+            bind_address,
+            mode,
+            spoof_source: false,
+            max_preamble_length: 4096,
+            max_replay_length: 16384,
+            max_body_capture: 1024 * 1024,
+            max_websocket_capture: 16 * 1024 * 1024,
+            max_websocket_messages: 10_000,
+            fallback: FallbackAction::Drop,
+            sniff_timeout: None,
+            reject_legacy_tls: false,
+            socks_credentials: None,
+            accept_proxy_protocol: false,
+            upstream_proxy_protocol: None,
+        }
+    }
+}
+
+/// State shared between all listeners and connections
+pub struct SharedState {
+    /// CA used to sign intercepted certificates
+    pub ca: SigningCA,
+    /// rustls crypto provider
+    pub crypto_provider: Arc<CryptoProvider>,
+    /// Cache of certificates minted by `ca`
+    pub certificates: CertificateCache,
+    /// Decides which TLS connections are intercepted
+    pub policy: InterceptPolicy,
+    /// Destination for flow records
+    pub flows: FlowRecorder,
+    /// Hosts which rejected minted certificates, if learning is enabled
+    pub learned: Option<LearnedPassthrough>,
+    /// Opens TLS connections to upstream servers of intercepted connections
+    pub upstream: UpstreamConnector,
+    /// Copy details of upstream certificates into minted certificates
+    pub mimic_certificates: bool,
+    /// Cleartext upstreams which are sent HTTP/2 with prior knowledge
+    pub h2c_upstreams: Vec<HostPattern>,
+    /// Pseudonym added to `Via` headers of forwarded messages, if any
+    pub via: Option<String>,
+}
+
+pub struct Listener {
+    shared: Arc<SharedState>,
+    config: Arc<ListenerConfig>,
+    socket: TcpListener,
+}
 
 impl Listener {
-    pub fn new() -> Self {
-        todo!();
+    /// Bind a new listener
+    pub async fn new(shared: Arc<SharedState>, config: ListenerConfig) -> eyre::Result<Self> {
+        if config.spoof_source && config.mode != ListenerMode::Tproxy {
+            eyre::bail!("source address spoofing requires TPROXY mode");
+        }
+        if config.max_preamble_length > config.max_replay_length {
+            eyre::bail!("maximum preamble length cannot exceed maximum replay length");
+        }
+        let socket = match config.mode {
+            ListenerMode::Tproxy => transparent::bind_tproxy(config.bind_address),
+            _ => TcpListener::bind(config.bind_address).await,
+        }
+        .wrap_err_with(|| format!("failed to bind listener on {}", config.bind_address))?;
+
+        Ok(Listener {
+            shared,
+            config: Arc::new(config),
+            socket,
+        })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Accept connections forever
+    pub async fn run(self) -> eyre::Result<()> {
+        info!(address = %self.local_addr()?, mode = ?self.config.mode, "listener started");
+        loop {
+            let (stream, peer_addr) = match self.socket.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    // usually fd exhaustion, back off a little so we don't spin
+                    error!(?err, "failed to accept connection");
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let local_addr = match stream.local_addr() {
+                Ok(addr) => addr,
+                Err(err) => {
+                    debug!(?err, %peer_addr, "failed to get local address of connection");
+                    continue;
+                }
+            };
+
+            let original_destination = match self.config.mode {
+                ListenerMode::Explicit => None,
+                ListenerMode::Transparent => match transparent::original_destination(&stream) {
+                    Ok(destination) => destination,
+                    Err(err) => {
+                        warn!(?err, %peer_addr, "failed to get original destination");
+                        continue;
+                    }
+                },
+                ListenerMode::Tproxy => {
+                    // connections made directly to the listener are not intercepted
+                    let bound = self.config.bind_address;
+                    if local_addr.port() == bound.port()
+                        && (local_addr.ip() == bound.ip() || bound.ip().is_unspecified())
+                    {
+                        None
+                    } else {
+                        Some(local_addr)
+                    }
+                }
+            };
+
+            let handler = ConnectionHandler {
+                shared: Arc::clone(&self.shared),
+                config: Arc::clone(&self.config),
+                peer_addr,
+                local_addr,
+                original_destination,
+                proxied: None,
+            };
+            tokio::spawn(async move {
+                if let Err(err) = handler.handle(stream).await {
+                    debug!(?err, %peer_addr, "connection handler exited with error");
+                }
+            });
+        }
     }
 }
 
+/// Per-connection handler
+#[derive(Clone)]
+pub struct ConnectionHandler {
+    pub(crate) shared: Arc<SharedState>,
+    pub(crate) config: Arc<ListenerConfig>,
+    pub(crate) peer_addr: SocketAddr,
+    pub(crate) local_addr: SocketAddr,
+    /// Original destination of a transparently redirected connection
+    pub(crate) original_destination: Option<SocketAddr>,
+    /// Proxy the connection was received through, if it sent a PROXY header
+    pub(crate) proxied: Option<ProxiedConnection>,
+}
+
+impl ConnectionHandler {
+    pub async fn handle(mut self, mut stream: TcpStream) -> eyre::Result<()> {
+        if self.config.accept_proxy_protocol
+            && let Some(header) = tokio::time::timeout(
+                proxy_protocol::HEADER_TIMEOUT,
+                ProxyHeader::read(&mut stream),
+            )
+            .await
+            .wrap_err("timed out waiting for PROXY header")??
+        {
+            debug!(
+                peer_addr = %self.peer_addr,
+                source = %header.source,
+                destination = %header.destination,
+                "received PROXY header"
+            );
+            self.proxied = Some(ProxiedConnection {
+                proxy_addr: self.peer_addr,
+                destination: header.destination,
+            });
+            self.peer_addr = header.source;
+            // the proxy's view of the destination replaces our own
+            if self.config.mode != ListenerMode::Explicit {
+                self.original_destination = Some(header.destination);
+            }
+        }
+
+        let mut stream = ReplayBuffer::new(stream, self.config.max_replay_length);
+        let state = sniff(
+            &mut stream,
+            self.config.max_preamble_length,
+            self.config.sniff_timeout,
+        )
+        .await?;
+        // TLS handling needs to read the rest of the ClientHello first
+        if state != PreambleState::ACCEPT_TLS {
+            stream.rewind();
+        }
+        debug!(
+            peer_addr = %self.peer_addr,
+            local_addr = %self.local_addr,
+            original_destination = ?self.original_destination,
+            ?state,
+            "sniffed protocol"
+        );
+
+        let target = self.original_destination.map(Target::from_addr);
+        match state {
+            PreambleState::ACCEPT_TLS => self.handle_tls(stream, target).await,
+            PreambleState::ACCEPT_LEGACY_TLS => self.handle_legacy_tls(stream, target).await,
+            PreambleState::ACCEPT_HTTP1 => {
+                let route = target.map(UpstreamRoute::plain);
+                self.handle_http1(stream, route, None).await
+            }
+            PreambleState::ACCEPT_HTTP2 => {
+                let route = target.map(UpstreamRoute::plain);
+                self.handle_http2(stream, route, None).await
+            }
+            PreambleState::ACCEPT_SOCKS4 | PreambleState::ACCEPT_SOCKS5
+                if self.config.mode == ListenerMode::Explicit =>
+            {
+                let version = if state == PreambleState::ACCEPT_SOCKS4 {
+                    4
+                } else {
+                    5
+                };
+                self.handle_socks(stream, version).await
+            }
+            PreambleState::REJECT | PreambleState::ACCEPT_SOCKS4 | PreambleState::ACCEPT_SOCKS5 => {
+                self.handle_unrecognized(stream, target).await
+            }
+            _ => unreachable!("sniff returned non-final state"),
+        }
+    }
+
+    /// Connect to upstream, spoofing the client address and sending a PROXY
+    /// header if configured
+    pub async fn connect_upstream(&self, target: &Target) -> std::io::Result<TcpStream> {
+        let mut stream = if self.config.spoof_source {
+            self.connect_spoofed(target).await?
+        } else {
+            target.connect().await?
+        };
+        if let Some(version) = self.config.upstream_proxy_protocol {
+            let header = ProxyHeader {
+                source: self.peer_addr,
+                destination: stream.peer_addr()?,
+            };
+            stream.write_all(&header.encode(version)).await?;
+        }
+        Ok(stream)
+    }
+
+    async fn connect_spoofed(&self, target: &Target) -> std::io::Result<TcpStream> {
+        let source = self.peer_addr.ip().to_canonical();
+        let address = target
+            .resolve()
+            .await?
+            .into_iter()
+            .find(|address| address.is_ipv4() == source.is_ipv4())
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::AddrNotAvailable,
+                    "no upstream address in the same family as the client",
+                )
+            })?;
+        transparent::connect_spoofed(source, address).await
+    }
+
+    /// Connect to upstream and perform a TLS handshake
+    ///
+    /// `server_name` is the SNI hostname sent by the client, the target host
+    /// is used if there is none. `alpn` is offered to upstream as is.
+    pub async fn connect_upstream_tls(
+        &self,
+        target: &Target,
+        server_name: Option<&str>,
+        alpn: &[Vec<u8>],
+    ) -> eyre::Result<TlsStream<TcpStream>> {
+        let stream = self
+            .connect_upstream(target)
+            .await
+            .wrap_err_with(|| format!("failed to connect to {target}"))?;
+        let server_name = server_name.map_or_else(|| target.host.clone(), Host::parse);
+        self.shared
+            .upstream
+            .connect(stream, &server_name, alpn)
+            .await
+    }
+
+    /// Sniff and dispatch a stream tunneled to `target`
+    pub(crate) async fn handle_tunnel(
+        &self,
+        stream: impl AsyncStream,
+        target: Target,
+    ) -> eyre::Result<()> {
+        let mut stream = ReplayBuffer::new(stream, self.config.max_replay_length);
+        let state = sniff(
+            &mut stream,
+            self.config.max_preamble_length,
+            self.config.sniff_timeout,
+        )
+        .await?;
+        if state != PreambleState::ACCEPT_TLS {
+            stream.rewind();
+        }
+        debug!(peer_addr = %self.peer_addr, %target, ?state, "sniffed tunneled protocol");
+
+        match state {
+            PreambleState::ACCEPT_TLS => self.handle_tls(stream, Some(target)).await,
+            PreambleState::ACCEPT_LEGACY_TLS => self.handle_legacy_tls(stream, Some(target)).await,
+            PreambleState::ACCEPT_HTTP1 => {
+                let route = UpstreamRoute::plain(target);
+                self.handle_http1(stream, Some(route), None).await
+            }
+            PreambleState::ACCEPT_HTTP2 => {
+                let route = UpstreamRoute::plain(target);
+                self.handle_http2(stream, Some(route), None).await
+            }
+            PreambleState::REJECT | PreambleState::ACCEPT_SOCKS4 | PreambleState::ACCEPT_SOCKS5 => {
+                self.handle_unrecognized(stream, Some(target)).await
+            }
+            _ => unreachable!("sniff returned non-final state"),
+        }
+    }
+
+    /// Intercept or pass through TLS to `target`
+    ///
+    /// `stream` must not have been rewound yet. If `target` is not known, the
+    /// SNI hostname is used instead.
+    async fn handle_tls(
+        &self,
+        mut stream: ReplayBuffer<impl AsyncStream>,
+        target: Option<Target>,
+    ) -> eyre::Result<()> {
+        let hello = client_hello::read_client_hello(&mut stream)
+            .await
+            .wrap_err("failed to read ClientHello")?;
+        stream.rewind();
+        let alpn: Vec<_> = hello
+            .alpn
+            .iter()
+            .map(|p| String::from_utf8_lossy(p))
+            .collect();
+        debug!(
+            peer_addr = %self.peer_addr,
+            server_name = ?hello.server_name,
+            ?alpn,
+            max_version = format_args!("{:#06x}", hello.max_version()),
+            "received ClientHello"
+        );
+
+        let target = target.or_else(|| {
+            let server_name = hello.server_name.as_deref()?;
+            Some(Target {
+                host: Host::parse(server_name),
+                port: 443,
+            })
+        });
+
+        // the diagnostic host is always intercepted and answered locally, so
+        // neither policy nor learning can make it unreachable
+        let sni_diagnostic = hello
+            .server_name
+            .as_deref()
+            .is_some_and(|name| diagnostics::is_diagnostic_host(&Host::parse(name)));
+        let target = match target {
+            Some(target) if sni_diagnostic => Some(Target {
+                host: Host::parse(diagnostics::DIAGNOSTIC_HOST),
+                port: target.port,
+            }),
+            target => target,
+        };
+        let diagnostic = target
+            .as_ref()
+            .is_some_and(|target| diagnostics::is_diagnostic_host(&target.host));
+
+        let policy_host = hello
+            .server_name
+            .as_deref()
+            .or_else(|| target.as_ref().and_then(|target| target.host.name()));
+        let learned = policy_host.is_some_and(|host| {
+            self.shared
+                .learned
+                .as_ref()
+                .is_some_and(|learned| learned.contains(host))
+        });
+        // connections without any hostname are always intercepted
+        if let Some(passthrough_target) = &target
+            && !diagnostic
+            && (learned || self.shared.policy.decide(policy_host) == TlsAction::Passthrough)
+        {
+            let passthrough_target = passthrough_target.clone();
+            return self
+                .handle_passthrough(stream, passthrough_target, hello.server_name.clone())
+                .await;
+        }
+
+        // without a target, the client connected directly to the listener
+        // without SNI so the certificate is issued for the listener address
+        let fallback_host = match &target {
+            Some(target) => target.host.clone(),
+            None => Host::Address(self.local_addr.ip()),
+        };
+        let Some(mut names) = tls::names_for(hello.server_name.as_deref(), &fallback_host) else {
+            return Ok(());
+        };
+
+        // upstream is connected first with the client's ALPN list, limited to
+        // protocols which can be intercepted, so the client can be accepted
+        // with whatever upstream selected. failures are reported to the
+        // client once its handshake completes
+        let offered_alpn = tls::supported_alpn(&hello.alpn);
+        let upstream = match &target {
+            Some(_) if diagnostic => None,
+            Some(target) => Some(
+                self.connect_upstream_tls(target, hello.server_name.as_deref(), &offered_alpn)
+                    .await,
+            ),
+            None => None,
+        };
+        let upstream_alpn = match &upstream {
+            Some(Ok(upstream)) => upstream.get_ref().1.alpn_protocol(),
+            _ => None,
+        };
+        let alpn = tls::client_alpn(&hello.alpn, upstream_alpn);
+        debug!(
+            peer_addr = %self.peer_addr,
+            upstream_alpn = ?upstream_alpn.map(String::from_utf8_lossy),
+            "negotiated upstream ALPN"
+        );
+
+        let template = match &upstream {
+            Some(Ok(upstream)) if self.shared.mimic_certificates => self.mimic_template(upstream),
+            _ => None,
+        };
+        if let Some(template) = &template {
+            // the requested name is kept in case upstream's certificate does
+            // not cover it
+            names.extend(template.subject_alt_names.iter().cloned());
+        }
+
+        let certified_key = self
+            .shared
+            .certificates
+            .get_or_mint(
+                &self.shared.ca,
+                &self.shared.crypto_provider,
+                SanSet::new(names),
+                template.as_ref(),
+            )
+            .await;
+        let config = tls::server_config(
+            Arc::clone(&self.shared.crypto_provider),
+            certified_key,
+            alpn,
+        )?;
+
+        let stream = match TlsAcceptor::from(config).accept(stream).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                if !diagnostic
+                    && let Some(host) = policy_host
+                    && let Some(learned) = &self.shared.learned
+                    && tls::client_rejected_certificate(&err)
+                {
+                    learned.learn(host).await;
+                }
+                return Err(err).wrap_err("TLS handshake with client failed");
+            }
+        };
+
+        let route = target.map(|target| UpstreamRoute {
+            target,
+            tls: true,
+            server_name: hello.server_name.clone(),
+        });
+        self.handle_decrypted(stream, route, upstream).await
+    }
+
+    /// Copy details of the certificate presented by upstream
+    fn mimic_template(&self, upstream: &TlsStream<TcpStream>) -> Option<MimicTemplate> {
+        let leaf = upstream.get_ref().1.peer_certificates()?.first()?;
+        match MimicTemplate::from_der(leaf) {
+            Ok(template) => Some(template),
+            Err(err) => {
+                warn!(?err, peer_addr = %self.peer_addr, "failed to parse upstream certificate");
+                None
+            }
+        }
+    }
+
+    /// Pass through an SSLv2-compatible ClientHello, which rustls cannot
+    /// accept, if allowed and the destination is known
+    async fn handle_legacy_tls(
+        &self,
+        stream: ReplayBuffer<impl AsyncStream>,
+        target: Option<Target>,
+    ) -> eyre::Result<()> {
+        // there is no SNI in SSLv2 hellos to find the destination from
+        let reason = match (&target, self.config.reject_legacy_tls) {
+            (_, true) => "legacy TLS is rejected by configuration",
+            (None, false) => "no destination to pass legacy TLS through to",
+            (Some(target), false) => {
+                return self.handle_passthrough(stream, target.clone(), None).await;
+            }
+        };
+        debug!(peer_addr = %self.peer_addr, ?target, reason, "dropping SSLv2-compatible ClientHello");
+        Ok(())
+    }
+
+    /// Apply the listener's fallback to a connection in an unrecognized
+    /// protocol
+    ///
+    /// `stream` must have been rewound.
+    async fn handle_unrecognized(
+        &self,
+        stream: ReplayBuffer<impl AsyncStream>,
+        target: Option<Target>,
+    ) -> eyre::Result<()> {
+        let fallback = self.config.fallback;
+        let target = match (fallback, target) {
+            (FallbackAction::Drop, target) => {
+                debug!(peer_addr = %self.peer_addr, ?target, "unrecognized protocol, dropping connection");
+                return Ok(());
+            }
+            (_, None) => {
+                debug!(peer_addr = %self.peer_addr, "unrecognized protocol without a destination, dropping connection");
+                return Ok(());
+            }
+            (_, Some(target)) => target,
+        };
+        if fallback == FallbackAction::Relay {
+            return self.handle_passthrough(stream, target, None).await;
+        }
+
+        debug!(peer_addr = %self.peer_addr, %target, "capturing unrecognized protocol");
+        let started_at = OffsetDateTime::now_utc();
+        let start = Instant::now();
+        let limit = self.config.max_body_capture;
+        let mut sent = StreamCapture::new(Direction::ClientToUpstream, limit);
+        let mut received = StreamCapture::new(Direction::UpstreamToClient, limit);
+
+        let stats = match self.connect_upstream(&target).await {
+            Ok(upstream) => {
+                relay::relay_inspected(
+                    stream,
+                    upstream,
+                    |data| sent.push(data),
+                    |data| received.push(data),
+                )
+                .await
+            }
+            Err(err) => relay::RelayStats {
+                error: Some(err),
+                ..Default::default()
+            },
+        };
+
+        self.shared
+            .flows
+            .record(FlowRecord::RawStream(Box::new(RawStreamFlow {
+                peer_addr: self.peer_addr,
+                proxied: self.proxied,
+                target,
+                started_at,
+                duration: start.elapsed(),
+                bytes_sent: stats.bytes_sent,
+                bytes_received: stats.bytes_received,
+                chunks: sent.merge(received),
+                error: stats.error.as_ref().map(ToString::to_string),
+            })));
+        Ok(())
+    }
+
+    /// Relay to `target` without interception, replaying sniffed bytes
+    async fn handle_passthrough(
+        &self,
+        stream: ReplayBuffer<impl AsyncStream>,
+        target: Target,
+        server_name: Option<String>,
+    ) -> eyre::Result<()> {
+        debug!(peer_addr = %self.peer_addr, %target, "passing through connection");
+        let started_at = OffsetDateTime::now_utc();
+        let start = Instant::now();
+
+        let stats = match self.connect_upstream(&target).await {
+            Ok(upstream) => relay::relay(stream, upstream).await,
+            Err(err) => relay::RelayStats {
+                error: Some(err),
+                ..Default::default()
+            },
+        };
+
+        self.shared
+            .flows
+            .record(FlowRecord::Passthrough(Box::new(PassthroughFlow {
+                peer_addr: self.peer_addr,
+                proxied: self.proxied,
+                target,
+                server_name,
+                started_at,
+                duration: start.elapsed(),
+                bytes_sent: stats.bytes_sent,
+                bytes_received: stats.bytes_received,
+                error: stats.error.as_ref().map(ToString::to_string),
+            })));
+        Ok(())
+    }
+
+    /// Sniff and dispatch a stream after TLS has been terminated
+    ///
+    /// If connecting to upstream failed, clients are sent an error response
+    /// describing the failure.
+    async fn handle_decrypted(
+        &self,
+        stream: impl AsyncStream,
+        route: Option<UpstreamRoute>,
+        upstream: Option<eyre::Result<TlsStream<TcpStream>>>,
+    ) -> eyre::Result<()> {
+        let mut stream = ReplayBuffer::new(stream, self.config.max_replay_length);
+        let state = sniff(&mut stream, self.config.max_preamble_length, None).await?;
+        stream.rewind();
+
+        let upstream = match upstream {
+            Some(Ok(upstream)) => Some(upstream),
+            Some(Err(err)) => {
+                let target = route.as_ref().map(|route| &route.target);
+                warn!(peer_addr = %self.peer_addr, ?target, ?err, "upstream TLS connection failed");
+                return match state {
+                    PreambleState::ACCEPT_HTTP1 => {
+                        let message = format!("{err:#}\n");
+                        self.serve_http1_error(stream, StatusCode::BAD_GATEWAY, message)
+                            .await
+                    }
+                    PreambleState::ACCEPT_HTTP2 => {
+                        let message = format!("{err:#}\n");
+                        self.serve_http2_error(stream, StatusCode::BAD_GATEWAY, message)
+                            .await
+                    }
+                    _ => Ok(()),
+                };
+            }
+            None => None,
+        };
+
+        match state {
+            PreambleState::ACCEPT_HTTP1 => {
+                // the client did not follow the protocol upstream selected, so
+                // the connection can't be reused
+                let upstream = upstream.filter(|upstream| {
+                    upstream.get_ref().1.alpn_protocol() != Some(tls::ALPN_HTTP2)
+                });
+                self.handle_http1(stream, route, upstream).await
+            }
+            PreambleState::ACCEPT_HTTP2 => {
+                let upstream = upstream.filter(|upstream| {
+                    upstream.get_ref().1.alpn_protocol() == Some(tls::ALPN_HTTP2)
+                });
+                self.handle_http2(stream, route, upstream).await
+            }
+            _ => {
+                debug!(peer_addr = %self.peer_addr, ?state, "unrecognized protocol inside TLS");
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Read from stream until the protocol is determined
+///
+/// Bytes read are recorded in the replay buffer, which is not rewound. If
+/// `idle_timeout` is set and the client sends nothing for that long, the
+/// protocol is unrecognized, as the server probably speaks first.
+pub async fn sniff<T: AsyncRead + Unpin>(
+    stream: &mut ReplayBuffer<T>,
+    max_length: usize,
+    idle_timeout: Option<Duration>,
+) -> eyre::Result<PreambleState> {
+    let mut machine = BigFunnyStateMachine::new();
+    let mut chunk = [0u8; 512];
+    loop {
+        // never read past the limit, so the replay buffer can't overflow
+        let remaining = max_length.saturating_sub(stream.recorded().len());
+        if remaining == 0 {
+            return Ok(machine.give_up());
+        }
+        let idle_timeout = idle_timeout.filter(|_| stream.recorded().is_empty());
+        let read = stream.read(&mut chunk[..remaining.min(512)]);
+        let result = match idle_timeout {
+            Some(idle_timeout) => match tokio::time::timeout(idle_timeout, read).await {
+                Ok(result) => result,
+                Err(_) => return Ok(PreambleState::REJECT),
+            },
+            None => read.await,
+        };
+        let count = result.wrap_err("failed to read preamble")?;
+        if count == 0 {
+            eyre::bail!("connection closed while sniffing");
+        }
+
+        for &byte in &chunk[..count] {
+            machine = machine.next(byte);
+            if machine.is_final() {
+                return Ok(machine.state);
+            }
+        }
+    }
+}
+
+/// Whether `byte` may appear in a token, such as a method (RFC 9110 5.6.2)
+fn is_tchar(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
 #[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PreambleState {
     INIT,
     TLS,
-    CONNECT,
-    DELETE,
-    GET,
-    HEAD,
-    OPTIONS,
-    P0,
-    HTTP2, // PRI
-    POST,
-    PUT,
-    PATCH,
-    TRACE, // perhaps handle this one locally for fun
+    /// Method token of an HTTP request line
+    METHOD,
+    /// Request target, up to the space before the version
+    TARGET,
+    /// `HTTP/` and the major version
+    VERSION,
+    /// Minor version of HTTP/1
+    HTTP1,
+    /// Remainder of the HTTP/2 connection preface
+    HTTP2,
+    /// SSLv2-compatible ClientHello
+    SSL2,
+    /// SSLv2-compatible ClientHello with client version major 0x00
+    SSL2_V2,
+    /// SSLv2-compatible ClientHello with client version major 0x03
+    SSL2_V3,
+    SOCKS4,
+    SOCKS5,
     REJECT,
     ACCEPT_TLS,
+    /// SSLv2-compatible ClientHello, which cannot be intercepted
+    ACCEPT_LEGACY_TLS,
     ACCEPT_HTTP1,
     ACCEPT_HTTP2,
+    ACCEPT_SOCKS4,
+    ACCEPT_SOCKS5,
 }
 
 pub struct BigFunnyStateMachine {
     state: PreambleState,
     index: usize,
+    /// Index at which the current state was entered
+    start: usize,
+    /// Whether every byte so far matches the HTTP/2 connection preface
+    preface: bool,
 }
 
 impl BigFunnyStateMachine {
@@ -48,6 +874,39 @@ impl BigFunnyStateMachine {
         BigFunnyStateMachine {
             state: PreambleState::INIT,
             index: 0,
+            start: 0,
+            preface: true,
+        }
+    }
+
+    pub fn state(&self) -> PreambleState {
+        self.state
+    }
+
+    /// Whether the state machine has reached a final state
+    pub fn is_final(&self) -> bool {
+        use PreambleState::*;
+        matches!(
+            self.state,
+            REJECT
+                | ACCEPT_TLS
+                | ACCEPT_LEGACY_TLS
+                | ACCEPT_HTTP1
+                | ACCEPT_HTTP2
+                | ACCEPT_SOCKS4
+                | ACCEPT_SOCKS5
+        )
+    }
+
+    /// Final state once the preamble limit is reached
+    ///
+    /// A request line is taken as HTTP/1 once `method SP target SP HTTP/1.`
+    /// has been seen, even if the minor version was cut off by the limit.
+    pub fn give_up(&self) -> PreambleState {
+        use PreambleState::*;
+        match self.state {
+            HTTP1 if self.index - self.start == 1 => ACCEPT_HTTP1,
+            _ => REJECT,
         }
     }
 
@@ -72,55 +931,84 @@ impl BigFunnyStateMachine {
             }};
         }
 
+        // index within the current state
+        let offset = self.index - self.start;
         let next_state = match (self.state, self.index, byte) {
             (INIT, 0, 0x16) => TLS,
-            (INIT, 0, b'C') => CONNECT,
-            (INIT, 0, b'D') => DELETE,
-            (INIT, 0, b'G') => GET,
-            (INIT, 0, b'H') => HEAD,
-            (INIT, 0, b'O') => OPTIONS,
-            (INIT, 0, b'P') => P0,
-            (INIT, 0, b'T') => TRACE,
+            // two byte SSLv2 record header with the high bit set
+            (INIT, 0, 0x80..) => SSL2,
+            (INIT, 0, 0x04) => SOCKS4,
+            (INIT, 0, 0x05) => SOCKS5,
+            (INIT, 0, byte) if is_tchar(byte) => METHOD,
             (INIT, 0, _) => REJECT,
             (INIT, _, _) => unreachable!("bad INIT state"),
             (_, 0, _) => unreachable!("state should be INIT"),
+            // record version: SSL 3.0 through TLS 1.3
             (TLS, 1, 0x03) => TLS,
-            (TLS, 2, 0x01) => TLS,
+            (TLS, 2, 0x00..=0x04) => TLS,
             (TLS, 3 | 4, _) => TLS,
             (TLS, 5, 0x01) => ACCEPT_TLS,
             (TLS, _, _) => REJECT,
-            (P0, 1, b'O') => POST,
-            (P0, 1, b'U') => PUT,
-            (P0, 1, b'A') => PATCH,
-            (P0, 1, b'R') => HTTP2,
-            (P0, 1, _) => REJECT,
-            (P0, _, _) => unreachable!("bad P0 state"),
-            (CONNECT, idx, byte) => literal_state!(CONNECT, idx, byte, b"CONNECT ", ACCEPT_HTTP1),
-            (DELETE, idx, byte) => literal_state!(DELETE, idx, byte, b"DELETE ", ACCEPT_HTTP1),
-            (GET, idx, byte) => literal_state!(GET, idx, byte, b"GET ", ACCEPT_HTTP1),
-            (HEAD, idx, byte) => literal_state!(HEAD, idx, byte, b"HEAD ", ACCEPT_HTTP1),
-            (OPTIONS, idx, byte) => literal_state!(OPTIONS, idx, byte, b"OPTIONS ", ACCEPT_HTTP1),
-            (HTTP2, idx, byte) => {
-                literal_state!(
-                    HTTP2,
-                    idx,
-                    byte,
-                    b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n",
-                    ACCEPT_HTTP2
-                )
+            (SSL2, 1, _) => SSL2,
+            (SSL2, 2, 0x01) => SSL2,
+            // client version: SSL 2.0 (0x0002) or SSL 3.0 through TLS 1.3
+            (SSL2, 3, 0x00) => SSL2_V2,
+            (SSL2, 3, 0x03) => SSL2_V3,
+            (SSL2, _, _) => REJECT,
+            (SSL2_V2, 4, 0x02) => ACCEPT_LEGACY_TLS,
+            (SSL2_V2, _, _) => REJECT,
+            (SSL2_V3, 4, 0x00..=0x04) => ACCEPT_LEGACY_TLS,
+            (SSL2_V3, _, _) => REJECT,
+            (METHOD, _, b' ') => TARGET,
+            (METHOD, _, byte) if is_tchar(byte) => METHOD,
+            (METHOD, _, _) => REJECT,
+            (TARGET, _, b' ') if offset > 0 => VERSION,
+            // anything visible, other characters are escaped in valid targets
+            (TARGET, _, byte) if byte > b' ' && byte != 0x7f => TARGET,
+            (TARGET, _, _) => REJECT,
+            (VERSION, _, byte) if offset < 5 => {
+                if byte == b"HTTP/"[offset] {
+                    VERSION
+                } else {
+                    REJECT
+                }
+            }
+            (VERSION, _, b'1') => HTTP1,
+            // only the connection preface, never a request, is HTTP/2
+            (VERSION, _, b'2') if self.preface => HTTP2,
+            (VERSION, _, _) => REJECT,
+            (HTTP1, _, b'.') if offset == 0 => HTTP1,
+            (HTTP1, _, byte) if offset == 1 && byte.is_ascii_digit() => ACCEPT_HTTP1,
+            (HTTP1, _, _) => REJECT,
+            (HTTP2, _, byte) => {
+                literal_state!(HTTP2, offset, byte, b".0\r\n\r\nSM\r\n\r\n", ACCEPT_HTTP2)
             }
-            (POST, idx, byte) => literal_state!(POST, idx, byte, b"POST ", ACCEPT_HTTP1),
-            (PUT, idx, byte) => literal_state!(PUT, idx, byte, b"PUT ", ACCEPT_HTTP1),
-            (PATCH, idx, byte) => literal_state!(PATCH, idx, byte, b"PATCH ", ACCEPT_HTTP1),
-            (TRACE, idx, byte) => literal_state!(TRACE, idx, byte, b"TRACE ", ACCEPT_HTTP1),
-            (REJECT | ACCEPT_TLS | ACCEPT_HTTP1 | ACCEPT_HTTP2, _, _) => {
+            // command: CONNECT or BIND
+            (SOCKS4, 1, 0x01 | 0x02) => ACCEPT_SOCKS4,
+            (SOCKS4, _, _) => REJECT,
+            // number of authentication methods
+            (SOCKS5, 1, 1..) => ACCEPT_SOCKS5,
+            (SOCKS5, _, _) => REJECT,
+            (
+                REJECT | ACCEPT_TLS | ACCEPT_LEGACY_TLS | ACCEPT_HTTP1 | ACCEPT_HTTP2
+                | ACCEPT_SOCKS4 | ACCEPT_SOCKS5,
+                _,
+                _,
+            ) => {
                 unreachable!("did not exit after final state")
             }
         };
 
+        let index = self.index + 1;
         BigFunnyStateMachine {
             state: next_state,
-            index: self.index + 1,
+            index,
+            start: if next_state == self.state {
+                self.start
+            } else {
+                index
+            },
+            preface: self.preface && h2c::PREFACE.get(self.index) == Some(&byte),
         }
     }
 }
@@ -130,3 +1018,115 @@ impl Default for BigFunnyStateMachine {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{BigFunnyStateMachine, PreambleState, sniff};
+    use crate::replay_buffer::ReplayBuffer;
+
+    fn run(input: &[u8]) -> PreambleState {
+        let mut machine = BigFunnyStateMachine::new();
+        for &byte in input {
+            machine = machine.next(byte);
+            if machine.is_final() {
+                break;
+            }
+        }
+        machine.state()
+    }
+
+    #[test]
+    fn detect_protocols() {
+        assert_eq!(run(b"GET / HTTP/1.1\r\n"), PreambleState::ACCEPT_HTTP1);
+        assert_eq!(run(b"PATCH /x HTTP/1.1\r\n"), PreambleState::ACCEPT_HTTP1);
+        assert_eq!(
+            run(b"PROPFIND /dav/ HTTP/1.1\r\n"),
+            PreambleState::ACCEPT_HTTP1
+        );
+        assert_eq!(
+            run(b"CONNECT example.com:443 HTTP/1.0\r\n"),
+            PreambleState::ACCEPT_HTTP1
+        );
+        assert_eq!(run(b"X-CUSTOM * HTTP/1.1\r\n"), PreambleState::ACCEPT_HTTP1);
+        assert_eq!(
+            run(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n"),
+            PreambleState::ACCEPT_HTTP2
+        );
+        assert_eq!(
+            run(&[0x16, 0x03, 0x01, 0x02, 0x00, 0x01]),
+            PreambleState::ACCEPT_TLS
+        );
+        assert_eq!(
+            run(&[0x16, 0x03, 0x03, 0x02, 0x00, 0x01]),
+            PreambleState::ACCEPT_TLS
+        );
+        assert_eq!(
+            run(&[0x80, 0x2e, 0x01, 0x03, 0x01]),
+            PreambleState::ACCEPT_LEGACY_TLS
+        );
+        assert_eq!(
+            run(&[0x80, 0x2e, 0x01, 0x00, 0x02]),
+            PreambleState::ACCEPT_LEGACY_TLS
+        );
+        assert_eq!(run(&[0x80, 0x2e, 0x01, 0x00, 0x03]), PreambleState::REJECT);
+        assert_eq!(run(&[0x80, 0x2e, 0x01, 0x03, 0x05]), PreambleState::REJECT);
+        assert_eq!(run(&[0x16, 0x03, 0x05]), PreambleState::REJECT);
+        assert_eq!(run(&[0x05, 0x01, 0x00]), PreambleState::ACCEPT_SOCKS5);
+        assert_eq!(run(&[0x04, 0x01, 0x01, 0xbb]), PreambleState::ACCEPT_SOCKS4);
+        assert_eq!(run(&[0x05, 0x00]), PreambleState::REJECT);
+        assert_eq!(run(b"SSH-2.0-OpenSSH_9.6\r\n"), PreambleState::REJECT);
+        assert_eq!(run(b"GET  / HTTP/1.1\r\n"), PreambleState::REJECT);
+        assert_eq!(run(b"GET /\r\n"), PreambleState::REJECT);
+        assert_eq!(run(b"GET / HTTP/3.0\r\n"), PreambleState::REJECT);
+        assert_eq!(run(b"GET / HTTP/2.0\r\nHost: x\r\n"), PreambleState::REJECT);
+        assert_eq!(run(b"GE(T / HTTP/1.1\r\n"), PreambleState::REJECT);
+    }
+
+    async fn sniff_at(input: &[u8], max_length: usize) -> PreambleState {
+        let mut stream = ReplayBuffer::new(input, max_length);
+        let state = sniff(&mut stream, max_length, None).await.unwrap();
+        assert!(stream.recorded().len() <= max_length);
+        state
+    }
+
+    #[tokio::test]
+    async fn sniff_limit() {
+        let request = b"GET / HTTP/1.1\r\n";
+        assert_eq!(sniff_at(request, 13).await, PreambleState::ACCEPT_HTTP1);
+        assert_eq!(sniff_at(request, 12).await, PreambleState::REJECT);
+        assert_eq!(sniff_at(request, 6).await, PreambleState::REJECT);
+
+        let long_target = format!("GET /{} HTTP/1.1\r\n", "a".repeat(100));
+        assert_eq!(
+            sniff_at(long_target.as_bytes(), 64).await,
+            PreambleState::REJECT
+        );
+        assert_eq!(
+            sniff_at(long_target.as_bytes(), 256).await,
+            PreambleState::ACCEPT_HTTP1
+        );
+
+        let garbage = format!("HELLO {}", "x".repeat(100));
+        assert_eq!(
+            sniff_at(garbage.as_bytes(), 64).await,
+            PreambleState::REJECT
+        );
+        let long_token = "A".repeat(100);
+        assert_eq!(
+            sniff_at(long_token.as_bytes(), 64).await,
+            PreambleState::REJECT
+        );
+
+        let preface = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+        assert_eq!(sniff_at(preface, 64).await, PreambleState::ACCEPT_HTTP2);
+        assert_eq!(sniff_at(preface, 20).await, PreambleState::REJECT);
+        for input in [
+            &b"GET * HTTP/2.0\r\n\r\nSM\r\n\r\n"[..],
+            b"PRI / HTTP/2.0\r\n\r\nSM\r\n\r\n",
+            b"PUT * HTTP/2.0\r\n\r\nSM\r\n\r\n",
+            b"PRIX * HTTP/2.0\r\n\r\nSM\r\n\r\n",
+        ] {
+            assert_eq!(sniff_at(input, 64).await, PreambleState::REJECT);
+        }
+    }
+}
@@ -0,0 +1,333 @@
+//! SOCKS4, SOCKS4a and SOCKS5 proxy handshakes
+//!
+//! Only the CONNECT command is supported. Success is reported to the client
+//! before connecting upstream, as with HTTP CONNECT, so the tunneled protocol
+//! can be sniffed first.
+
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use eyre::{Context, bail};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::debug;
+
+use crate::common::AsyncStream;
+use crate::server::ConnectionHandler;
+use crate::target::{Host, Target};
+
+const SOCKS4_VERSION: u8 = 0x04;
+const SOCKS5_VERSION: u8 = 0x05;
+const COMMAND_CONNECT: u8 = 0x01;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+/// Version of the username/password subnegotiation (RFC 1929)
+const USERNAME_PASSWORD_VERSION: u8 = 0x01;
+
+const ADDRESS_IPV4: u8 = 0x01;
+const ADDRESS_DOMAIN: u8 = 0x03;
+const ADDRESS_IPV6: u8 = 0x04;
+
+const SOCKS5_SUCCEEDED: u8 = 0x00;
+const SOCKS5_GENERAL_FAILURE: u8 = 0x01;
+const SOCKS5_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const SOCKS5_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+const SOCKS4_GRANTED: u8 = 0x5a;
+const SOCKS4_REJECTED: u8 = 0x5b;
+
+/// Longest user ID or hostname accepted in a SOCKS4 request
+const MAX_SOCKS4_STRING: usize = 255;
+
+/// Username and password SOCKS clients must authenticate with
+#[derive(Clone, PartialEq, Eq)]
+pub struct SocksCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl fmt::Debug for SocksCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SocksCredentials")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FromStr for SocksCredentials {
+    type Err = eyre::Report;
+
+    /// Parse from `username:password`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((username, password)) = s.split_once(':') else {
+            bail!("SOCKS credentials must be of the form username:password");
+        };
+        if username.len() > 255 || password.len() > 255 {
+            bail!("SOCKS username and password must be at most 255 bytes");
+        }
+        Ok(SocksCredentials {
+            username: username.to_owned(),
+            password: password.to_owned(),
+        })
+    }
+}
+
+fn socks5_reply(code: u8) -> [u8; 10] {
+    // bound address is not meaningful since upstream is not connected yet
+    [SOCKS5_VERSION, code, 0, ADDRESS_IPV4, 0, 0, 0, 0, 0, 0]
+}
+
+/// Perform a SOCKS5 handshake, returning the requested target
+///
+/// If `credentials` is `None`, clients may choose not to authenticate, and
+/// any username and password are accepted from those which do.
+pub async fn socks5_handshake(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    credentials: Option<&SocksCredentials>,
+) -> eyre::Result<Target> {
+    let version = stream.read_u8().await?;
+    if version != SOCKS5_VERSION {
+        bail!("unexpected SOCKS version {version}");
+    }
+    let method_count = stream.read_u8().await?;
+    let mut methods = vec![0u8; method_count as usize];
+    stream.read_exact(&mut methods).await?;
+
+    let method = if credentials.is_none() && methods.contains(&METHOD_NO_AUTH) {
+        METHOD_NO_AUTH
+    } else if methods.contains(&METHOD_USERNAME_PASSWORD) {
+        METHOD_USERNAME_PASSWORD
+    } else {
+        stream
+            .write_all(&[SOCKS5_VERSION, METHOD_NONE_ACCEPTABLE])
+            .await?;
+        bail!("no acceptable SOCKS5 authentication method offered");
+    };
+    stream.write_all(&[SOCKS5_VERSION, method]).await?;
+
+    if method == METHOD_USERNAME_PASSWORD {
+        let version = stream.read_u8().await?;
+        if version != USERNAME_PASSWORD_VERSION {
+            bail!("unexpected SOCKS5 username/password version {version}");
+        }
+        let mut username = vec![0u8; stream.read_u8().await? as usize];
+        stream.read_exact(&mut username).await?;
+        let mut password = vec![0u8; stream.read_u8().await? as usize];
+        stream.read_exact(&mut password).await?;
+
+        let accepted = credentials.is_none_or(|credentials| {
+            credentials.username.as_bytes() == username
+                && credentials.password.as_bytes() == password
+        });
+        let status = if accepted { 0x00 } else { 0x01 };
+        stream
+            .write_all(&[USERNAME_PASSWORD_VERSION, status])
+            .await?;
+        if !accepted {
+            bail!(
+                "SOCKS5 authentication failed for user {:?}",
+                String::from_utf8_lossy(&username)
+            );
+        }
+    }
+
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request).await?;
+    let [version, command, _, address_type] = request;
+    if version != SOCKS5_VERSION {
+        bail!("unexpected SOCKS version {version} in request");
+    }
+    let host = match address_type {
+        ADDRESS_IPV4 => {
+            let mut address = [0u8; 4];
+            stream.read_exact(&mut address).await?;
+            Host::Address(Ipv4Addr::from(address).into())
+        }
+        ADDRESS_DOMAIN => {
+            let mut name = vec![0u8; stream.read_u8().await? as usize];
+            stream.read_exact(&mut name).await?;
+            match String::from_utf8(name) {
+                Ok(name) => Host::parse(&name),
+                Err(_) => {
+                    stream
+                        .write_all(&socks5_reply(SOCKS5_GENERAL_FAILURE))
+                        .await?;
+                    bail!("SOCKS5 domain name is not valid UTF-8");
+                }
+            }
+        }
+        ADDRESS_IPV6 => {
+            let mut address = [0u8; 16];
+            stream.read_exact(&mut address).await?;
+            Host::Address(Ipv6Addr::from(address).to_canonical())
+        }
+        other => {
+            stream
+                .write_all(&socks5_reply(SOCKS5_ADDRESS_TYPE_NOT_SUPPORTED))
+                .await?;
+            bail!("unsupported SOCKS5 address type {other}");
+        }
+    };
+    let port = stream.read_u16().await?;
+
+    if command != COMMAND_CONNECT {
+        stream
+            .write_all(&socks5_reply(SOCKS5_COMMAND_NOT_SUPPORTED))
+            .await?;
+        bail!("unsupported SOCKS5 command {command}");
+    }
+    stream.write_all(&socks5_reply(SOCKS5_SUCCEEDED)).await?;
+    Ok(Target { host, port })
+}
+
+async fn read_null_terminated(stream: &mut (impl AsyncRead + Unpin)) -> eyre::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    loop {
+        match stream.read_u8().await? {
+            0 => return Ok(out),
+            _ if out.len() == MAX_SOCKS4_STRING => bail!("SOCKS4 string too long"),
+            byte => out.push(byte),
+        }
+    }
+}
+
+/// Perform a SOCKS4 or SOCKS4a handshake, returning the requested target
+///
+/// SOCKS4 has no passwords, so requests are rejected if `credentials` is set.
+pub async fn socks4_handshake(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    credentials: Option<&SocksCredentials>,
+) -> eyre::Result<Target> {
+    let mut request = [0u8; 8];
+    stream.read_exact(&mut request).await?;
+    let [version, command, port @ .., a, b, c, d] = request;
+    if version != SOCKS4_VERSION {
+        bail!("unexpected SOCKS version {version}");
+    }
+    let port = u16::from_be_bytes(port);
+    let user_id = read_null_terminated(stream).await?;
+    // SOCKS4a: an address of 0.0.0.x means a hostname follows
+    let host = if [a, b, c] == [0, 0, 0] && d != 0 {
+        let name = read_null_terminated(stream).await?;
+        Host::parse(&String::from_utf8_lossy(&name))
+    } else {
+        Host::Address(Ipv4Addr::new(a, b, c, d).into())
+    };
+
+    let reject = if command != COMMAND_CONNECT {
+        Some(format!("unsupported SOCKS4 command {command}"))
+    } else if credentials.is_some() {
+        Some(format!(
+            "SOCKS4 request from user {:?} cannot be authenticated",
+            String::from_utf8_lossy(&user_id)
+        ))
+    } else {
+        None
+    };
+    let status = if reject.is_some() {
+        SOCKS4_REJECTED
+    } else {
+        SOCKS4_GRANTED
+    };
+    stream.write_all(&[0, status, 0, 0, 0, 0, 0, 0]).await?;
+    if let Some(reject) = reject {
+        bail!(reject);
+    }
+    Ok(Target { host, port })
+}
+
+impl ConnectionHandler {
+    /// Complete a SOCKS handshake and intercept the tunneled stream
+    pub(crate) async fn handle_socks(
+        &self,
+        mut stream: impl AsyncStream,
+        version: u8,
+    ) -> eyre::Result<()> {
+        let credentials = self.config.socks_credentials.as_ref();
+        let target = if version == SOCKS4_VERSION {
+            socks4_handshake(&mut stream, credentials).await
+        } else {
+            socks5_handshake(&mut stream, credentials).await
+        }
+        .wrap_err("SOCKS handshake failed")?;
+        debug!(peer_addr = %self.peer_addr, %target, version, "SOCKS CONNECT");
+        self.handle_tunnel(stream, target).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{SocksCredentials, socks4_handshake, socks5_handshake};
+    use crate::target::{Host, Target};
+
+    #[tokio::test]
+    async fn socks5_domain() {
+        let (mut client, mut server) = tokio::io::duplex(256);
+        let credentials: SocksCredentials = "user:secret".parse().unwrap();
+        let handshake =
+            tokio::spawn(async move { socks5_handshake(&mut server, Some(&credentials)).await });
+
+        // offers no authentication and username/password
+        client.write_all(&[5, 2, 0, 2]).await.unwrap();
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, 2]);
+
+        client.write_all(b"\x01\x04user\x06secret").await.unwrap();
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [1, 0]);
+
+        client
+            .write_all(b"\x05\x01\x00\x03\x0bexample.com\x01\xbb")
+            .await
+            .unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [5, 0]);
+
+        let target = handshake.await.unwrap().unwrap();
+        assert_eq!(
+            target,
+            Target {
+                host: Host::Name("example.com".to_owned()),
+                port: 443,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn socks5_wrong_password() {
+        let (mut client, mut server) = tokio::io::duplex(256);
+        let credentials: SocksCredentials = "user:secret".parse().unwrap();
+        let handshake =
+            tokio::spawn(async move { socks5_handshake(&mut server, Some(&credentials)).await });
+
+        client.write_all(&[5, 1, 2]).await.unwrap();
+        client.write_all(b"\x01\x04user\x05wrong").await.unwrap();
+        let mut reply = [0u8; 4];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, 2, 1, 1]);
+        assert!(handshake.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn socks4a_hostname() {
+        let (mut client, mut server) = tokio::io::duplex(256);
+        let handshake = tokio::spawn(async move { socks4_handshake(&mut server, None).await });
+
+        client
+            .write_all(b"\x04\x01\x00\x50\x00\x00\x00\x01user\0example.com\0")
+            .await
+            .unwrap();
+        let mut reply = [0u8; 8];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [0, 0x5a]);
+
+        let target = handshake.await.unwrap().unwrap();
+        assert_eq!(target.to_string(), "example.com:80");
+    }
+}
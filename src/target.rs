@@ -0,0 +1,92 @@
+//! Upstream targets
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+use hyper::http::uri::Authority;
+use tokio::net::{TcpStream, lookup_host};
+
+/// Host part of a target
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Host {
+    Name(String),
+    Address(IpAddr),
+}
+
+impl Host {
+    /// Parse a hostname or address, IPv6 addresses may be enclosed in brackets
+    pub fn parse(host: &str) -> Self {
+        let unbracketed = host
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+            .unwrap_or(host);
+        match unbracketed.parse::<IpAddr>() {
+            Ok(address) => Host::Address(address.to_canonical()),
+            Err(_) => Host::Name(host.to_ascii_lowercase()),
+        }
+    }
+
+    /// Hostname, if this is not an address
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Host::Name(name) => Some(name),
+            Host::Address(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Host {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Host::Name(name) => f.write_str(name),
+            Host::Address(address) => address.fmt(f),
+        }
+    }
+}
+
+/// Upstream host and port
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Target {
+    pub host: Host,
+    pub port: u16,
+}
+
+impl Target {
+    pub fn from_addr(address: SocketAddr) -> Self {
+        Target {
+            host: Host::Address(address.ip().to_canonical()),
+            port: address.port(),
+        }
+    }
+
+    /// Parse from a URI authority, using `default_port` if none is specified
+    pub fn from_authority(authority: &Authority, default_port: u16) -> Self {
+        Target {
+            host: Host::parse(authority.host()),
+            port: authority.port_u16().unwrap_or(default_port),
+        }
+    }
+
+    pub async fn resolve(&self) -> std::io::Result<Vec<SocketAddr>> {
+        match &self.host {
+            Host::Name(name) => Ok(lookup_host((name.as_str(), self.port)).await?.collect()),
+            Host::Address(address) => Ok(vec![SocketAddr::new(*address, self.port)]),
+        }
+    }
+
+    pub async fn connect(&self) -> std::io::Result<TcpStream> {
+        match &self.host {
+            Host::Name(name) => TcpStream::connect((name.as_str(), self.port)).await,
+            Host::Address(address) => TcpStream::connect((*address, self.port)).await,
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.host {
+            Host::Address(IpAddr::V6(address)) => write!(f, "[{address}]:{}", self.port),
+            host => write!(f, "{host}:{}", self.port),
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! TLS interception
+
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use eyre::Context;
+use rcgen::SanType;
+use rustls::crypto::CryptoProvider;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{AlertDescription, ServerConfig};
+use tracing::warn;
+
+use crate::target::Host;
+
+pub const ALPN_HTTP1: &[u8] = b"http/1.1";
+pub const ALPN_HTTP2: &[u8] = b"h2";
+
+/// Determine names a minted certificate should be issued for
+///
+/// The certificate is issued for the SNI hostname, or for `fallback` if the
+/// client did not send SNI.
+pub fn names_for(server_name: Option<&str>, fallback: &Host) -> Option<Vec<SanType>> {
+    let name = match server_name {
+        Some(name) => match name.parse::<IpAddr>() {
+            // not allowed by the RFC but some clients do it anyways
+            Ok(address) => SanType::IpAddress(address),
+            Err(_) => match name.try_into() {
+                Ok(name) => SanType::DnsName(name),
+                Err(err) => {
+                    warn!(?err, name, "invalid SNI hostname");
+                    return None;
+                }
+            },
+        },
+        None => match fallback {
+            Host::Address(address) => SanType::IpAddress(address.to_canonical()),
+            Host::Name(name) => match name.as_str().try_into() {
+                Ok(name) => SanType::DnsName(name),
+                Err(err) => {
+                    warn!(?err, name, "invalid fallback hostname");
+                    return None;
+                }
+            },
+        },
+    };
+    Some(vec![name])
+}
+
+/// Serves a certificate minted before the handshake started
+#[derive(Debug)]
+pub struct MintedCertResolver(pub Arc<CertifiedKey>);
+
+impl ResolvesServerCert for MintedCertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(Arc::clone(&self.0))
+    }
+}
+
+/// Create server config for accepting an intercepted connection
+///
+/// `alpn` is the protocol to select if the client offers ALPN, usually the
+/// one selected by upstream.
+pub fn server_config(
+    crypto_provider: Arc<CryptoProvider>,
+    certified_key: Arc<CertifiedKey>,
+    alpn: Option<Vec<u8>>,
+) -> eyre::Result<Arc<ServerConfig>> {
+    let mut config = ServerConfig::builder_with_provider(crypto_provider)
+        .with_safe_default_protocol_versions()
+        .wrap_err("failed to create TLS server config")?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(MintedCertResolver(certified_key)));
+    config.alpn_protocols = alpn.into_iter().collect();
+    Ok(Arc::new(config))
+}
+
+/// Whether intercepted connections can be served over `protocol`
+fn is_supported_alpn(protocol: &[u8]) -> bool {
+    protocol == ALPN_HTTP2 || protocol == ALPN_HTTP1
+}
+
+/// Protocols from the client's ALPN list which may be offered upstream
+pub fn supported_alpn(offered: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    offered
+        .iter()
+        .filter(|protocol| is_supported_alpn(protocol))
+        .cloned()
+        .collect()
+}
+
+/// Choose the protocol to accept the client with
+///
+/// Mirrors the protocol upstream selected, if it can be served. Otherwise
+/// HTTP/1.1 is selected if the client offered it, since that is what errors
+/// are served over.
+pub fn client_alpn(offered: &[Vec<u8>], upstream: Option<&[u8]>) -> Option<Vec<u8>> {
+    match upstream {
+        Some(protocol) if is_supported_alpn(protocol) => Some(protocol.to_vec()),
+        _ => offered
+            .iter()
+            .find(|protocol| protocol.as_slice() == ALPN_HTTP1)
+            .cloned(),
+    }
+}
+
+/// Whether a failed handshake was aborted by the client because it did not
+/// trust the certificate we served
+pub fn client_rejected_certificate(err: &io::Error) -> bool {
+    let alert = err
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<rustls::Error>());
+    matches!(
+        alert,
+        Some(rustls::Error::AlertReceived(
+            AlertDescription::UnknownCA | AlertDescription::BadCertificate
+        ))
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ALPN_HTTP1, ALPN_HTTP2, client_alpn, supported_alpn};
+
+    #[test]
+    fn choose_client_alpn() {
+        let offered = vec![ALPN_HTTP2.to_vec(), ALPN_HTTP1.to_vec()];
+        // upstream's selection is mirrored
+        assert_eq!(
+            client_alpn(&offered, Some(ALPN_HTTP2)),
+            Some(ALPN_HTTP2.to_vec())
+        );
+        assert_eq!(
+            client_alpn(&offered, Some(ALPN_HTTP1)),
+            Some(ALPN_HTTP1.to_vec())
+        );
+        // without upstream, HTTP/1.1 only if the client offered it
+        assert_eq!(client_alpn(&offered, None), Some(ALPN_HTTP1.to_vec()));
+        assert_eq!(client_alpn(&[ALPN_HTTP2.to_vec()], None), None);
+        assert_eq!(client_alpn(&[], None), None);
+        // protocols which can't be intercepted are never echoed
+        let offered = vec![b"spdy/3.1".to_vec(), ALPN_HTTP1.to_vec()];
+        assert_eq!(
+            client_alpn(&offered, Some(b"spdy/3.1")),
+            Some(ALPN_HTTP1.to_vec())
+        );
+        assert_eq!(
+            client_alpn(&[b"acme-tls/1".to_vec()], Some(b"acme-tls/1")),
+            None
+        );
+        assert_eq!(supported_alpn(&offered), [ALPN_HTTP1.to_vec()]);
+    }
+}
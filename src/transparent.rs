@@ -0,0 +1,120 @@
+//! Transparent proxy support
+//!
+//! Connections redirected with iptables REDIRECT arrive at the listener with
+//! their original destination recorded by conntrack, for example:
+//!
+//! ```text
+//! iptables -t nat -A PREROUTING -p tcp --dport 443 -j REDIRECT --to-ports 8081
+//! ip6tables -t nat -A PREROUTING -p tcp --dport 443 -j REDIRECT --to-ports 8081
+//! ```
+//!
+//! To test locally, run a client in a network namespace whose traffic is routed
+//! through the host and redirected there.
+//!
+//! Alternatively, TPROXY delivers connections without NAT to a listener bound
+//! with `IP_TRANSPARENT`, in which case the local address of the accepted
+//! socket is the original destination:
+//!
+//! ```text
+//! ip rule add fwmark 1 lookup 100
+//! ip route add local 0.0.0.0/0 dev lo table 100
+//! iptables -t mangle -A PREROUTING -p tcp --dport 443 -j TPROXY --on-port 8082 --tproxy-mark 1
+//! ```
+//!
+//! Spoofing the client address on upstream connections additionally requires
+//! replies from upstream to be routed back to the proxy, e.g. by marking them
+//! with `-m socket --transparent` and routing with the same table.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+/// Get the original destination of a connection redirected by iptables
+///
+/// Returns `None` if the connection was not redirected.
+#[cfg(target_os = "linux")]
+pub fn original_destination(stream: &TcpStream) -> io::Result<Option<SocketAddr>> {
+    let socket = socket2::SockRef::from(stream);
+    let local_addr = stream.local_addr()?;
+    let original = if local_addr.ip().to_canonical().is_ipv4() {
+        socket.original_dst_v4()
+    } else {
+        socket.original_dst_v6()
+    };
+    let original = match original {
+        Ok(original) => original,
+        // no conntrack entry, so not redirected
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let original = original.as_socket().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "original destination is not an inet address",
+        )
+    })?;
+
+    // connections made directly to the listener report the listener address
+    if original.ip().to_canonical() == local_addr.ip().to_canonical()
+        && original.port() == local_addr.port()
+    {
+        Ok(None)
+    } else {
+        Ok(Some(original))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn original_destination(_stream: &TcpStream) -> io::Result<Option<SocketAddr>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "transparent mode is only supported on Linux",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn set_transparent(socket: &TcpSocket, address: &SocketAddr) -> io::Result<()> {
+    let socket = socket2::SockRef::from(socket);
+    if address.is_ipv4() {
+        socket.set_ip_transparent_v4(true)
+    } else {
+        socket.set_ip_transparent_v6(true)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_transparent(_socket: &TcpSocket, _address: &SocketAddr) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TPROXY is only supported on Linux",
+    ))
+}
+
+/// Bind a listener with `IP_TRANSPARENT` for use with TPROXY
+pub fn bind_tproxy(address: SocketAddr) -> io::Result<TcpListener> {
+    let socket = if address.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    set_transparent(&socket, &address)?;
+    socket.set_reuseaddr(true)?;
+    socket.bind(address)?;
+    socket.listen(1024)
+}
+
+/// Connect to `target` using `source` as the local address
+///
+/// `source` does not need to be an address of this host.
+pub async fn connect_spoofed(source: IpAddr, target: SocketAddr) -> io::Result<TcpStream> {
+    let source = SocketAddr::new(source, 0);
+    let socket = if target.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    set_transparent(&socket, &source)?;
+    socket.bind(source)?;
+    socket.connect(target).await
+}
@@ -0,0 +1,291 @@
+//! TLS connections to upstream servers
+
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use eyre::{Context, bail};
+use hyper::Uri;
+use hyper::http::uri::Scheme;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::client::TlsStream;
+use tracing::{info, warn};
+
+use crate::target::{Host, Target};
+
+/// Which certificates are trusted for upstream connections
+///
+/// Parsed from `webpki`, `system`, `insecure`, or `file:<path>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpstreamTrust {
+    /// Mozilla roots bundled with webpki-roots
+    Webpki,
+    /// Roots from the operating system certificate store
+    System,
+    /// Roots from a PEM bundle
+    File(PathBuf),
+    /// Accept any certificate, for testing against self-signed servers
+    Insecure,
+}
+
+impl FromStr for UpstreamTrust {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "webpki" => Ok(UpstreamTrust::Webpki),
+            "system" => Ok(UpstreamTrust::System),
+            "insecure" => Ok(UpstreamTrust::Insecure),
+            _ => match s.strip_prefix("file:") {
+                Some(path) => Ok(UpstreamTrust::File(path.into())),
+                None => bail!("unknown upstream trust mode {s:?}"),
+            },
+        }
+    }
+}
+
+impl fmt::Display for UpstreamTrust {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamTrust::Webpki => f.write_str("webpki"),
+            UpstreamTrust::System => f.write_str("system"),
+            UpstreamTrust::File(path) => write!(f, "file:{}", path.display()),
+            UpstreamTrust::Insecure => f.write_str("insecure"),
+        }
+    }
+}
+
+fn root_store(trust: &UpstreamTrust) -> eyre::Result<RootCertStore> {
+    let certs: Vec<CertificateDer<'static>> = match trust {
+        UpstreamTrust::Webpki => {
+            return Ok(RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            });
+        }
+        UpstreamTrust::System => {
+            let result = rustls_native_certs::load_native_certs();
+            for err in &result.errors {
+                warn!(%err, "error loading system certificates");
+            }
+            result.certs
+        }
+        UpstreamTrust::File(path) => CertificateDer::pem_file_iter(path)
+            .and_then(|certs| certs.collect())
+            .wrap_err_with(|| format!("failed to read CA bundle {}", path.display()))?,
+        UpstreamTrust::Insecure => Vec::new(),
+    };
+
+    let mut roots = RootCertStore::empty();
+    let (added, ignored) = roots.add_parsable_certificates(certs);
+    info!(%trust, added, ignored, "loaded upstream trust roots");
+    if roots.is_empty() {
+        bail!("no usable trust roots for {trust}");
+    }
+    Ok(roots)
+}
+
+/// Accepts any server certificate
+///
+/// Handshake signatures are still checked so that the connection is at least
+/// consistent with the certificate presented.
+#[derive(Debug)]
+struct InsecureVerifier {
+    crypto_provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for InsecureVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.crypto_provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.crypto_provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.crypto_provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// Where requests on an intercepted connection are forwarded to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamRoute {
+    pub target: Target,
+    /// Whether upstream is connected to over TLS
+    pub tls: bool,
+    /// SNI hostname sent by the client, if any
+    pub server_name: Option<String>,
+}
+
+impl UpstreamRoute {
+    pub fn plain(target: Target) -> Self {
+        UpstreamRoute {
+            target,
+            tls: false,
+            server_name: None,
+        }
+    }
+
+    /// Route for an absolute-form request URI, or `None` if `uri` is in
+    /// origin-form
+    pub fn from_absolute_uri(uri: &Uri) -> eyre::Result<Option<Self>> {
+        let (Some(scheme), Some(authority)) = (uri.scheme(), uri.authority()) else {
+            return Ok(None);
+        };
+        let tls = if *scheme == Scheme::HTTP {
+            false
+        } else if *scheme == Scheme::HTTPS {
+            true
+        } else {
+            bail!("unsupported URI scheme {scheme}");
+        };
+        let target = Target::from_authority(authority, if tls { 443 } else { 80 });
+        Ok(Some(UpstreamRoute {
+            target,
+            tls,
+            server_name: None,
+        }))
+    }
+}
+
+/// Opens TLS connections to upstream servers
+pub struct UpstreamConnector {
+    config: Arc<ClientConfig>,
+}
+
+impl UpstreamConnector {
+    pub fn new(crypto_provider: Arc<CryptoProvider>, trust: &UpstreamTrust) -> eyre::Result<Self> {
+        let builder = ClientConfig::builder_with_provider(Arc::clone(&crypto_provider))
+            .with_safe_default_protocol_versions()
+            .wrap_err("failed to create TLS client config")?;
+        let config = match trust {
+            UpstreamTrust::Insecure => {
+                warn!("upstream certificates will not be verified");
+                builder
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(InsecureVerifier {
+                        crypto_provider,
+                    }))
+                    .with_no_client_auth()
+            }
+            trust => builder
+                .with_root_certificates(root_store(trust)?)
+                .with_no_client_auth(),
+        };
+        Ok(UpstreamConnector {
+            config: Arc::new(config),
+        })
+    }
+
+    /// Perform a TLS handshake over `stream`
+    ///
+    /// `server_name` is sent as SNI and used to verify the certificate. `alpn`
+    /// is the list of protocols to offer, in order of preference.
+    pub async fn connect(
+        &self,
+        stream: TcpStream,
+        server_name: &Host,
+        alpn: &[Vec<u8>],
+    ) -> eyre::Result<TlsStream<TcpStream>> {
+        let server_name = match server_name {
+            Host::Name(name) => ServerName::try_from(name.clone())
+                .wrap_err_with(|| format!("invalid upstream server name {name:?}"))?,
+            Host::Address(address) => ServerName::IpAddress((*address).into()),
+        };
+        let config = if alpn.is_empty() {
+            Arc::clone(&self.config)
+        } else {
+            let mut config = ClientConfig::clone(&self.config);
+            config.alpn_protocols = alpn.to_vec();
+            Arc::new(config)
+        };
+        TlsConnector::from(config)
+            .connect(server_name, stream)
+            .await
+            .wrap_err("upstream TLS handshake failed")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::Uri;
+
+    use super::{UpstreamRoute, UpstreamTrust};
+    use crate::target::{Host, Target};
+
+    #[test]
+    fn parse_trust() {
+        assert_eq!(
+            "webpki".parse::<UpstreamTrust>().unwrap(),
+            UpstreamTrust::Webpki
+        );
+        assert_eq!(
+            "file:/etc/ssl/ca.pem".parse::<UpstreamTrust>().unwrap(),
+            UpstreamTrust::File("/etc/ssl/ca.pem".into())
+        );
+        assert!("/etc/ssl/ca.pem".parse::<UpstreamTrust>().is_err());
+    }
+
+    #[test]
+    fn absolute_uri_route() {
+        let uri: Uri = "http://example.com/path".parse().unwrap();
+        let route = UpstreamRoute::from_absolute_uri(&uri).unwrap().unwrap();
+        assert_eq!(
+            route,
+            UpstreamRoute::plain(Target {
+                host: Host::Name("example.com".to_owned()),
+                port: 80,
+            })
+        );
+
+        let uri: Uri = "https://[::1]:8443/".parse().unwrap();
+        let route = UpstreamRoute::from_absolute_uri(&uri).unwrap().unwrap();
+        assert!(route.tls);
+        assert_eq!(route.target.to_string(), "[::1]:8443");
+
+        let uri: Uri = "/path".parse().unwrap();
+        assert!(UpstreamRoute::from_absolute_uri(&uri).unwrap().is_none());
+        let uri: Uri = "ftp://example.com/".parse().unwrap();
+        assert!(UpstreamRoute::from_absolute_uri(&uri).is_err());
+    }
+}
@@ -0,0 +1,699 @@
+//! WebSocket interception
+//!
+//! Upgraded connections are relayed byte for byte, while a copy of each
+//! direction is parsed into messages for the flow record. Fragmented messages
+//! are reassembled and permessage-deflate messages are decompressed. Parsing
+//! stops for a direction at the first malformed frame, but relaying goes on.
+//! Messages past the session's capture budget are counted but not recorded.
+//!
+//! HTTP/2 clients using extended CONNECT (RFC 8441) are translated to an
+//! HTTP/1.1 upgrade towards upstream, since few servers support it.
+
+use std::sync::Mutex;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use bytes::BytesMut;
+use flate2::{Decompress, FlushDecompress, Status};
+use hyper::ext::Protocol;
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+use hyper::{Method, Request, StatusCode, http};
+use time::OffsetDateTime;
+
+use crate::common::AsyncStream;
+use crate::flow::{Direction, WebSocketMessage, WebSocketOpcode, WebSocketSession};
+use crate::forward::has_token;
+use crate::relay;
+
+/// Longest possible frame header, with a 64-bit length and a mask
+const MAX_HEADER_LENGTH: usize = 14;
+/// Appended to compressed messages before decompressing (RFC 7692 section
+/// 7.2.2)
+const DEFLATE_TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+static SEC_WEBSOCKET_KEY: HeaderName = HeaderName::from_static("sec-websocket-key");
+static SEC_WEBSOCKET_ACCEPT: HeaderName = HeaderName::from_static("sec-websocket-accept");
+static SEC_WEBSOCKET_VERSION: HeaderName = HeaderName::from_static("sec-websocket-version");
+static SEC_WEBSOCKET_EXTENSIONS: HeaderName = HeaderName::from_static("sec-websocket-extensions");
+
+/// How a client asked to open a WebSocket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpgradeKind {
+    /// HTTP/1.1 `Upgrade: websocket`
+    Http1,
+    /// HTTP/2 extended CONNECT with `:protocol` websocket
+    ExtendedConnect,
+}
+
+impl UpgradeKind {
+    /// How `request` asks to open a WebSocket, if it does
+    pub fn of<B>(request: &Request<B>) -> Option<Self> {
+        if request.method() == Method::CONNECT {
+            let protocol = request.extensions().get::<Protocol>()?;
+            return protocol
+                .as_str()
+                .eq_ignore_ascii_case("websocket")
+                .then_some(UpgradeKind::ExtendedConnect);
+        }
+        let headers = request.headers();
+        (has_token(headers, &header::UPGRADE, "websocket")
+            && has_token(headers, &header::CONNECTION, "upgrade"))
+        .then_some(UpgradeKind::Http1)
+    }
+}
+
+fn insert_upgrade_headers(headers: &mut HeaderMap) {
+    headers.insert(header::CONNECTION, HeaderValue::from_static("Upgrade"));
+    headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+}
+
+/// Turn a request with hop-by-hop headers removed into an HTTP/1.1 upgrade
+/// request for upstream
+///
+/// `key` is used as `Sec-WebSocket-Key` for extended CONNECT requests, which
+/// do not have one.
+pub fn prepare_request(parts: &mut http::request::Parts, kind: UpgradeKind, key: [u8; 16]) {
+    if kind == UpgradeKind::ExtendedConnect {
+        parts.method = Method::GET;
+        parts.extensions.remove::<Protocol>();
+        let key = HeaderValue::from_str(&STANDARD.encode(key)).expect("base64 is a valid header");
+        parts.headers.insert(&SEC_WEBSOCKET_KEY, key);
+        if !parts.headers.contains_key(&SEC_WEBSOCKET_VERSION) {
+            parts
+                .headers
+                .insert(&SEC_WEBSOCKET_VERSION, HeaderValue::from_static("13"));
+        }
+    }
+    insert_upgrade_headers(&mut parts.headers);
+}
+
+/// Turn upstream's `101 Switching Protocols` response with hop-by-hop headers
+/// removed into the response expected by the client
+pub fn prepare_response(parts: &mut http::response::Parts, kind: UpgradeKind) {
+    match kind {
+        UpgradeKind::Http1 => insert_upgrade_headers(&mut parts.headers),
+        UpgradeKind::ExtendedConnect => {
+            parts.status = StatusCode::OK;
+            parts.headers.remove(&SEC_WEBSOCKET_ACCEPT);
+            parts.headers.remove(header::CONTENT_LENGTH);
+        }
+    }
+}
+
+/// Negotiated permessage-deflate parameters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeflateParams {
+    /// Client resets its compression context after each message
+    pub client_no_context_takeover: bool,
+    /// Server resets its compression context after each message
+    pub server_no_context_takeover: bool,
+}
+
+impl DeflateParams {
+    /// Parameters accepted in upstream's `Sec-WebSocket-Extensions`, if
+    /// permessage-deflate was negotiated
+    pub fn from_response_headers(headers: &HeaderMap) -> Option<Self> {
+        let extension = headers
+            .get_all(&SEC_WEBSOCKET_EXTENSIONS)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .find(|extension| {
+                extension
+                    .split(';')
+                    .next()
+                    .is_some_and(|name| name.trim().eq_ignore_ascii_case("permessage-deflate"))
+            })?;
+
+        let mut params = DeflateParams::default();
+        for param in extension.split(';').skip(1) {
+            let name = param.split('=').next().unwrap_or_default().trim();
+            if name.eq_ignore_ascii_case("client_no_context_takeover") {
+                params.client_no_context_takeover = true;
+            } else if name.eq_ignore_ascii_case("server_no_context_takeover") {
+                params.server_no_context_takeover = true;
+            }
+        }
+        Some(params)
+    }
+}
+
+/// Limits on what is recorded from one WebSocket connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CaptureLimits {
+    /// Maximum number of payload bytes recorded per message
+    pub message_bytes: usize,
+    /// Maximum number of payload bytes recorded across both directions
+    pub session_bytes: usize,
+    /// Maximum number of messages recorded across both directions
+    pub messages: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FrameHeader {
+    fin: bool,
+    rsv1: bool,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    length: u64,
+}
+
+impl FrameHeader {
+    fn is_control(&self) -> bool {
+        self.opcode & 0x8 != 0
+    }
+}
+
+/// Parse a frame header from the start of `buf`, returning it and its length
+///
+/// Returns `Ok(None)` if `buf` does not contain the whole header yet.
+fn parse_frame_header(buf: &[u8]) -> Result<Option<(FrameHeader, usize)>, &'static str> {
+    let [first, second, ..] = *buf else {
+        return Ok(None);
+    };
+    let (length, mut offset) = match second & 0x7f {
+        126 => match buf.get(2..4) {
+            Some(bytes) => (u16::from_be_bytes([bytes[0], bytes[1]]) as u64, 4),
+            None => return Ok(None),
+        },
+        127 => match buf.get(2..10) {
+            Some(bytes) => (u64::from_be_bytes(bytes.try_into().unwrap()), 10),
+            None => return Ok(None),
+        },
+        length => (length as u64, 2),
+    };
+    if length >> 63 != 0 {
+        return Err("frame length has most significant bit set");
+    }
+    let mask = if second & 0x80 != 0 {
+        let Some(mask) = buf.get(offset..offset + 4) else {
+            return Ok(None);
+        };
+        offset += 4;
+        Some(mask.try_into().unwrap())
+    } else {
+        None
+    };
+
+    let header = FrameHeader {
+        fin: first & 0x80 != 0,
+        rsv1: first & 0x40 != 0,
+        opcode: first & 0x0f,
+        mask,
+        length,
+    };
+    if header.is_control() && (!header.fin || header.length > 125) {
+        return Err("invalid control frame");
+    }
+    Ok(Some((header, offset)))
+}
+
+/// Message being reassembled
+struct PartialMessage {
+    opcode: WebSocketOpcode,
+    timestamp: OffsetDateTime,
+    data: BytesMut,
+    length: u64,
+    compressed: bool,
+    error: Option<String>,
+}
+
+impl PartialMessage {
+    fn new(opcode: u8, compressed: bool) -> Self {
+        PartialMessage {
+            opcode: opcode.into(),
+            timestamp: OffsetDateTime::now_utc(),
+            data: BytesMut::new(),
+            length: 0,
+            compressed,
+            error: None,
+        }
+    }
+
+    fn push(&mut self, data: &[u8], limit: usize) {
+        self.length += data.len() as u64;
+        let remaining = limit.saturating_sub(self.data.len());
+        self.data
+            .extend_from_slice(&data[..remaining.min(data.len())]);
+    }
+
+    fn finish(self, direction: Direction) -> WebSocketMessage {
+        WebSocketMessage {
+            direction,
+            opcode: self.opcode,
+            timestamp: self.timestamp,
+            payload: self.data.freeze(),
+            length: self.length,
+            compressed: self.compressed,
+            error: self.error,
+        }
+    }
+}
+
+/// permessage-deflate decompression state for one direction
+struct Inflater {
+    decompress: Decompress,
+    no_context_takeover: bool,
+    /// Set once decompression fails, after which the context is unusable
+    failed: bool,
+}
+
+impl Inflater {
+    fn new(no_context_takeover: bool) -> Self {
+        Inflater {
+            decompress: Decompress::new(false),
+            no_context_takeover,
+            failed: false,
+        }
+    }
+
+    /// Decompress `input` into `message`
+    fn inflate(&mut self, mut input: &[u8], message: &mut PartialMessage, limit: usize) {
+        if self.failed {
+            message
+                .error
+                .get_or_insert_with(|| "an earlier message failed to decompress".to_owned());
+            return;
+        }
+
+        let mut buf = [0u8; 16384];
+        loop {
+            let total_in = self.decompress.total_in();
+            let total_out = self.decompress.total_out();
+            let status = self
+                .decompress
+                .decompress(input, &mut buf, FlushDecompress::Sync);
+            let consumed = (self.decompress.total_in() - total_in) as usize;
+            let produced = (self.decompress.total_out() - total_out) as usize;
+            input = &input[consumed..];
+            message.push(&buf[..produced], limit);
+
+            match status {
+                Ok(Status::StreamEnd) => {
+                    // a final block ends the context, whatever was negotiated
+                    self.decompress.reset(false);
+                    if input.is_empty() {
+                        return;
+                    }
+                }
+                Ok(_) if input.is_empty() && produced < buf.len() => return,
+                Ok(_) if consumed == 0 && produced == 0 => return,
+                Ok(_) => {}
+                Err(err) => {
+                    message.error = Some(format!("decompression failed: {err}"));
+                    self.failed = true;
+                    return;
+                }
+            }
+        }
+    }
+
+    fn finish_message(&mut self, message: &mut PartialMessage, limit: usize) {
+        self.inflate(&DEFLATE_TRAILER, message, limit);
+        if self.no_context_takeover {
+            self.decompress.reset(false);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FrameState {
+    header: FrameHeader,
+    /// Payload bytes not yet seen
+    remaining: u64,
+    /// Position in the payload, for unmasking
+    position: usize,
+}
+
+/// Messages recorded from both directions of a connection
+struct MessageLog {
+    limits: CaptureLimits,
+    messages: Vec<WebSocketMessage>,
+    /// Payload bytes recorded so far
+    captured: usize,
+    /// Messages not recorded because the budget was used up
+    dropped: u64,
+}
+
+impl MessageLog {
+    fn new(limits: CaptureLimits) -> Self {
+        MessageLog {
+            limits,
+            messages: Vec::new(),
+            captured: 0,
+            dropped: 0,
+        }
+    }
+
+    fn push(&mut self, message: WebSocketMessage) {
+        let size = message.payload.len();
+        if self.messages.len() >= self.limits.messages
+            || self.captured + size > self.limits.session_bytes
+        {
+            self.dropped += 1;
+            return;
+        }
+        self.captured += size;
+        self.messages.push(message);
+    }
+}
+
+/// Parses one direction of a WebSocket connection into messages
+struct MessageParser {
+    direction: Direction,
+    /// Maximum number of payload bytes captured per message
+    limit: usize,
+    inflater: Option<Inflater>,
+    /// Partially received frame header
+    header: Vec<u8>,
+    frame: Option<FrameState>,
+    /// Data message being reassembled
+    message: Option<PartialMessage>,
+    /// Control frame being received, which may interrupt a data message
+    control: Option<PartialMessage>,
+    error: Option<&'static str>,
+}
+
+impl MessageParser {
+    /// `deflate_no_context_takeover` is set if permessage-deflate was
+    /// negotiated
+    fn new(direction: Direction, limit: usize, deflate_no_context_takeover: Option<bool>) -> Self {
+        MessageParser {
+            direction,
+            limit,
+            inflater: deflate_no_context_takeover.map(Inflater::new),
+            header: Vec::with_capacity(MAX_HEADER_LENGTH),
+            frame: None,
+            message: None,
+            control: None,
+            error: None,
+        }
+    }
+
+    /// Parse `data`, adding finished messages to `log`
+    fn feed(&mut self, mut data: &[u8], log: &mut MessageLog) {
+        while !data.is_empty() && self.error.is_none() {
+            let Some(mut frame) = self.frame else {
+                let previous = self.header.len();
+                let take = (MAX_HEADER_LENGTH - previous).min(data.len());
+                self.header.extend_from_slice(&data[..take]);
+                match parse_frame_header(&self.header) {
+                    Ok(None) => data = &data[take..],
+                    Ok(Some((header, length))) => {
+                        data = &data[length - previous..];
+                        self.header.clear();
+                        self.start_frame(header, log);
+                    }
+                    Err(err) => self.error = Some(err),
+                }
+                continue;
+            };
+
+            let count = frame.remaining.min(data.len() as u64) as usize;
+            let mut payload = data[..count].to_vec();
+            if let Some(mask) = frame.header.mask {
+                for (index, byte) in payload.iter_mut().enumerate() {
+                    *byte ^= mask[(frame.position + index) % 4];
+                }
+            }
+            frame.position += count;
+            frame.remaining -= count as u64;
+            data = &data[count..];
+            self.frame = Some(frame);
+            self.push_payload(frame.header, &payload);
+            if frame.remaining == 0 {
+                self.end_frame(frame.header, log);
+            }
+        }
+    }
+
+    fn start_frame(&mut self, header: FrameHeader, log: &mut MessageLog) {
+        if header.is_control() {
+            self.control = Some(PartialMessage::new(header.opcode, false));
+        } else if header.opcode == 0 {
+            if self.message.is_none() {
+                self.error = Some("continuation frame without a message");
+                return;
+            }
+        } else {
+            if self.message.is_some() {
+                self.error = Some("new message before the previous one finished");
+                return;
+            }
+            let compressed = header.rsv1 && self.inflater.is_some();
+            self.message = Some(PartialMessage::new(header.opcode, compressed));
+        }
+
+        self.frame = Some(FrameState {
+            header,
+            remaining: header.length,
+            position: 0,
+        });
+        if header.length == 0 {
+            self.end_frame(header, log);
+        }
+    }
+
+    fn push_payload(&mut self, header: FrameHeader, payload: &[u8]) {
+        if header.is_control() {
+            if let Some(control) = &mut self.control {
+                control.push(payload, self.limit);
+            }
+            return;
+        }
+        let Some(message) = &mut self.message else {
+            return;
+        };
+        match &mut self.inflater {
+            Some(inflater) if message.compressed => inflater.inflate(payload, message, self.limit),
+            _ => message.push(payload, self.limit),
+        }
+    }
+
+    fn end_frame(&mut self, header: FrameHeader, log: &mut MessageLog) {
+        self.frame = None;
+        if header.is_control() {
+            if let Some(control) = self.control.take() {
+                log.push(control.finish(self.direction));
+            }
+        } else if header.fin
+            && let Some(mut message) = self.message.take()
+        {
+            if let Some(inflater) = &mut self.inflater
+                && message.compressed
+            {
+                inflater.finish_message(&mut message, self.limit);
+            }
+            log.push(message.finish(self.direction));
+        }
+    }
+
+    /// Add any messages cut short by the connection closing to `log`
+    fn finish(mut self, log: &mut MessageLog) {
+        for mut message in [self.control.take(), self.message.take()]
+            .into_iter()
+            .flatten()
+        {
+            message.error = Some("connection closed before the message finished".to_owned());
+            log.push(message.finish(self.direction));
+        }
+    }
+}
+
+/// Relay an upgraded WebSocket connection, recording messages in both
+/// directions
+///
+/// `deflate` is the negotiated permessage-deflate configuration, if any.
+pub async fn relay_websocket(
+    client: impl AsyncStream,
+    upstream: impl AsyncStream,
+    deflate: Option<DeflateParams>,
+    limits: CaptureLimits,
+) -> WebSocketSession {
+    let mut sent = MessageParser::new(
+        Direction::ClientToUpstream,
+        limits.message_bytes,
+        deflate.map(|deflate| deflate.client_no_context_takeover),
+    );
+    let mut received = MessageParser::new(
+        Direction::UpstreamToClient,
+        limits.message_bytes,
+        deflate.map(|deflate| deflate.server_no_context_takeover),
+    );
+    // shared by both directions, which are never inspected at the same time
+    let log = Mutex::new(MessageLog::new(limits));
+    let stats = relay::relay_inspected(
+        client,
+        upstream,
+        |data| sent.feed(data, &mut log.lock().unwrap()),
+        |data| received.feed(data, &mut log.lock().unwrap()),
+    )
+    .await;
+
+    let errors = [sent.error, received.error];
+    let mut log = log.into_inner().unwrap();
+    sent.finish(&mut log);
+    received.finish(&mut log);
+    let mut messages = log.messages;
+    messages.sort_by_key(|message| message.timestamp);
+    let error = stats
+        .error
+        .map(|err| err.to_string())
+        .or_else(|| errors.into_iter().flatten().next().map(str::to_owned));
+    WebSocketSession {
+        messages,
+        dropped_messages: log.dropped,
+        bytes_sent: stats.bytes_sent,
+        bytes_received: stats.bytes_received,
+        error,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use flate2::{Compress, Compression, FlushCompress};
+    use hyper::HeaderMap;
+
+    use super::{CaptureLimits, DeflateParams, MessageLog, MessageParser};
+    use crate::flow::{Direction, WebSocketOpcode};
+
+    const LIMITS: CaptureLimits = CaptureLimits {
+        message_bytes: 1024,
+        session_bytes: 1024 * 1024,
+        messages: 100,
+    };
+
+    fn frame(first: u8, mask: Option<[u8; 4]>, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![first];
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+        match payload.len() {
+            length @ 0..=125 => frame.push(mask_bit | length as u8),
+            length @ 126..=0xffff => {
+                frame.push(mask_bit | 126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(mask_bit | 127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        match mask {
+            Some(mask) => {
+                frame.extend_from_slice(&mask);
+                frame.extend(
+                    payload
+                        .iter()
+                        .enumerate()
+                        .map(|(index, byte)| byte ^ mask[index % 4]),
+                );
+            }
+            None => frame.extend_from_slice(payload),
+        }
+        frame
+    }
+
+    #[test]
+    fn reassemble_fragments() {
+        let mask = Some([1, 2, 3, 4]);
+        let mut stream = frame(0x01, mask, b"hello ");
+        // ping interleaved with the fragmented message
+        stream.extend(frame(0x89, mask, b"ping"));
+        stream.extend(frame(0x80, mask, &[b'w'; 300]));
+        stream.extend(frame(0x82, mask, b"binary"));
+
+        let mut parser = MessageParser::new(Direction::ClientToUpstream, 10, None);
+        let mut log = MessageLog::new(LIMITS);
+        // feed one byte at a time to exercise partial headers and payloads
+        for byte in &stream {
+            parser.feed(std::slice::from_ref(byte), &mut log);
+        }
+        parser.finish(&mut log);
+        let messages = log.messages;
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0].opcode, WebSocketOpcode::Ping);
+        assert_eq!(&messages[0].payload[..], b"ping");
+        assert_eq!(messages[1].opcode, WebSocketOpcode::Text);
+        assert_eq!(&messages[1].payload[..], b"hello wwww");
+        assert_eq!(messages[1].length, 306);
+        assert!(messages[1].is_truncated());
+        assert_eq!(messages[2].opcode, WebSocketOpcode::Binary);
+        assert_eq!(&messages[2].payload[..], b"binary");
+        assert!(messages.iter().all(|message| message.error.is_none()));
+    }
+
+    #[test]
+    fn decompress_messages() {
+        let mut compress = Compress::new(Compression::default(), false);
+        let mut stream = Vec::new();
+        for text in [&b"compressed message"[..], b"compressed message again"] {
+            let mut output = Vec::with_capacity(128);
+            compress
+                .compress_vec(text, &mut output, FlushCompress::Sync)
+                .unwrap();
+            assert!(output.ends_with(&[0, 0, 0xff, 0xff]));
+            output.truncate(output.len() - 4);
+            // RSV1 marks a compressed message
+            stream.extend(frame(0xc1, None, &output));
+        }
+
+        let mut parser = MessageParser::new(Direction::UpstreamToClient, 1024, Some(false));
+        let mut log = MessageLog::new(LIMITS);
+        parser.feed(&stream, &mut log);
+        parser.finish(&mut log);
+        let messages = log.messages;
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].compressed);
+        assert_eq!(&messages[0].payload[..], b"compressed message");
+        assert_eq!(&messages[1].payload[..], b"compressed message again");
+    }
+
+    #[test]
+    fn session_budget() {
+        let mut stream = Vec::new();
+        for payload in [&b"first"[..], b"second", b"third", b"4th"] {
+            stream.extend(frame(0x81, None, payload));
+        }
+
+        let mut parser = MessageParser::new(Direction::UpstreamToClient, 1024, None);
+        let mut log = MessageLog::new(CaptureLimits {
+            session_bytes: 14,
+            ..LIMITS
+        });
+        parser.feed(&stream, &mut log);
+        parser.finish(&mut log);
+        // "third" would go past the budget, but "4th" still fits
+        assert_eq!(log.messages.len(), 3);
+        assert_eq!(&log.messages[2].payload[..], b"4th");
+        assert_eq!(log.dropped, 1);
+
+        let mut parser = MessageParser::new(Direction::UpstreamToClient, 1024, None);
+        let mut log = MessageLog::new(CaptureLimits {
+            messages: 2,
+            ..LIMITS
+        });
+        parser.feed(&stream, &mut log);
+        parser.finish(&mut log);
+        assert_eq!(log.messages.len(), 2);
+        assert_eq!(log.dropped, 2);
+    }
+
+    #[test]
+    fn parse_deflate_params() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(DeflateParams::from_response_headers(&headers), None);
+
+        headers.insert(
+            "sec-websocket-extensions",
+            "permessage-deflate; client_no_context_takeover; server_max_window_bits=10"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(
+            DeflateParams::from_response_headers(&headers),
+            Some(DeflateParams {
+                client_no_context_takeover: true,
+                server_no_context_takeover: false,
+            })
+        );
+    }
+}